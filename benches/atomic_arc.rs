@@ -0,0 +1,143 @@
+//! Loads of `AtomicArc` against the usual alternative, a `std::sync::Arc`
+//! cloned out of a `RwLock`, with and without a concurrent writer.
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use hash_map::atomic_arc::{Arc, AtomicArc};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Barrier, RwLock};
+use std::time::{Duration, Instant};
+
+trait Cell: Send + Sync {
+    const NAME: &'static str;
+
+    fn new(value: u64) -> Self;
+    fn read(&self) -> u64;
+    fn write(&self, value: u64);
+}
+
+impl Cell for AtomicArc<u64> {
+    const NAME: &'static str = "atomic_arc/load";
+
+    fn new(value: u64) -> Self {
+        AtomicArc::new(Arc::new(value))
+    }
+
+    fn read(&self) -> u64 {
+        *self.load()
+    }
+
+    fn write(&self, value: u64) {
+        self.store(Arc::new(value))
+    }
+}
+
+/// Same as `AtomicArc<u64>`, without taking a reference count.
+struct Guarded(AtomicArc<u64>);
+
+impl Cell for Guarded {
+    const NAME: &'static str = "atomic_arc/load_guard";
+
+    fn new(value: u64) -> Self {
+        Guarded(AtomicArc::new(Arc::new(value)))
+    }
+
+    fn read(&self) -> u64 {
+        *self.0.load_guard()
+    }
+
+    fn write(&self, value: u64) {
+        self.0.store(Arc::new(value))
+    }
+}
+
+impl Cell for RwLock<std::sync::Arc<u64>> {
+    const NAME: &'static str = "rwlock";
+
+    fn new(value: u64) -> Self {
+        RwLock::new(std::sync::Arc::new(value))
+    }
+
+    fn read(&self) -> u64 {
+        let arc = self.read().unwrap().clone();
+        *arc
+    }
+
+    fn write(&self, value: u64) {
+        *RwLock::write(self).unwrap() = std::sync::Arc::new(value);
+    }
+}
+
+fn thread_counts() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut counts = (0..)
+        .map(|i| 1 << i)
+        .take_while(|&n| n < cores)
+        .collect::<Vec<_>>();
+    counts.push(cores);
+    counts
+}
+
+/// Time `iters` reads on each of `threads` threads, while another thread
+/// keeps writing if `writer` is set, and return the time taken by the
+/// slowest reader.
+fn run<C: Cell>(cell: &C, threads: usize, iters: u64, writer: bool) -> Duration {
+    let barrier = Barrier::new(threads);
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        if writer {
+            s.spawn(|| {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    cell.write(i);
+                    i += 1;
+                }
+            });
+        }
+        let readers = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        black_box(cell.read());
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect::<Vec<_>>();
+        let elapsed = readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .max()
+            .unwrap();
+        done.store(true, Ordering::Relaxed);
+        elapsed
+    })
+}
+
+fn bench_cell<C: Cell>(group: &mut BenchmarkGroup<'_, WallTime>, threads: usize, writer: bool) {
+    let cell = C::new(0);
+    group.bench_function(BenchmarkId::new(C::NAME, threads), |b| {
+        b.iter_custom(|iters| run(&cell, threads, iters, writer))
+    });
+}
+
+fn loads(c: &mut Criterion) {
+    for (name, writer) in [("read", false), ("read_with_writer", true)] {
+        let mut group = c.benchmark_group(name);
+        for threads in thread_counts() {
+            group.throughput(Throughput::Elements(threads as u64));
+            bench_cell::<AtomicArc<u64>>(&mut group, threads, writer);
+            bench_cell::<Guarded>(&mut group, threads, writer);
+            bench_cell::<RwLock<std::sync::Arc<u64>>>(&mut group, threads, writer);
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, loads);
+criterion_main!(benches);
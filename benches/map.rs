@@ -0,0 +1,404 @@
+//! Throughput of `HashMap` against lock-based maps from std, on 1 thread up
+//! to the number of available cores. All the maps use the same hasher, so
+//! that only the data structures are compared.
+//!
+//! Each iteration runs one operation on each thread, with keys and
+//! operations drawn upfront so that their generation is not measured, and
+//! throughputs are given for all the threads.
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use hash_map::hash_map::{DefaultBuildHasher, HashMap};
+use std::collections::HashMap as StdHashMap;
+use std::hash::BuildHasher;
+use std::hint::black_box;
+use std::sync::{Barrier, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Number of distinct keys. Maps are filled with all of them beforehand,
+/// unless noted otherwise.
+const KEYS: u64 = 1 << 16;
+/// Number of keys and operations drawn for each thread, which cycles over
+/// them.
+const DRAWS: usize = 1 << 14;
+const SHARDS: usize = 16;
+
+trait Map: Send + Sync {
+    const NAME: &'static str;
+
+    fn with_capacity(capacity: usize) -> Self;
+    fn get(&self, key: u64) -> bool;
+    fn insert(&self, key: u64, value: u64);
+    fn remove(&self, key: u64);
+}
+
+impl Map for HashMap<u64, u64> {
+    const NAME: &'static str = "hash_map";
+
+    fn with_capacity(capacity: usize) -> Self {
+        let map = HashMap::new();
+        map.reserve(capacity);
+        map
+    }
+
+    fn get(&self, key: u64) -> bool {
+        HashMap::get(self, &key).is_some()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&self, key: u64) {
+        HashMap::remove(self, &key)
+    }
+}
+
+type StdMap = StdHashMap<u64, u64, DefaultBuildHasher>;
+
+fn std_map(capacity: usize) -> StdMap {
+    StdHashMap::with_capacity_and_hasher(capacity, Default::default())
+}
+
+impl Map for Mutex<StdMap> {
+    const NAME: &'static str = "mutex";
+
+    fn with_capacity(capacity: usize) -> Self {
+        Mutex::new(std_map(capacity))
+    }
+
+    fn get(&self, key: u64) -> bool {
+        self.lock().unwrap().get(&key).is_some()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        self.lock().unwrap().insert(key, value);
+    }
+
+    fn remove(&self, key: u64) {
+        self.lock().unwrap().remove(&key);
+    }
+}
+
+impl Map for RwLock<StdMap> {
+    const NAME: &'static str = "rwlock";
+
+    fn with_capacity(capacity: usize) -> Self {
+        RwLock::new(std_map(capacity))
+    }
+
+    fn get(&self, key: u64) -> bool {
+        self.read().unwrap().get(&key).is_some()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        self.write().unwrap().insert(key, value);
+    }
+
+    fn remove(&self, key: u64) {
+        self.write().unwrap().remove(&key);
+    }
+}
+
+/// `SHARDS` maps behind their own lock, picked by the high bits of the hash
+/// so that each shard still sees well distributed low bits.
+struct Sharded {
+    hash_builder: DefaultBuildHasher,
+    shards: Vec<RwLock<StdMap>>,
+}
+
+impl Sharded {
+    fn shard(&self, key: u64) -> &RwLock<StdMap> {
+        let hash = self.hash_builder.hash_one(key);
+        &self.shards[(hash >> 60) as usize % SHARDS]
+    }
+}
+
+impl Map for Sharded {
+    const NAME: &'static str = "sharded";
+
+    fn with_capacity(capacity: usize) -> Self {
+        Sharded {
+            hash_builder: Default::default(),
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(std_map(capacity / SHARDS)))
+                .collect(),
+        }
+    }
+
+    fn get(&self, key: u64) -> bool {
+        self.shard(key).read().unwrap().get(&key).is_some()
+    }
+
+    fn insert(&self, key: u64, value: u64) {
+        self.shard(key).write().unwrap().insert(key, value);
+    }
+
+    fn remove(&self, key: u64) {
+        self.shard(key).write().unwrap().remove(&key);
+    }
+}
+
+/// A xorshift generator, enough to draw keys.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: usize) -> Self {
+        Rng(0x9e37_79b9_7f4a_7c15 ^ (seed as u64 + 1).wrapping_mul(0xff51_afd7_ed55_8ccd))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return a float uniformly drawn in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Distribution {
+    Uniform,
+    /// Key `k` is drawn with a probability proportional to `1 / (k + 1)`.
+    Zipf,
+}
+
+impl Distribution {
+    fn name(self) -> &'static str {
+        match self {
+            Distribution::Uniform => "uniform",
+            Distribution::Zipf => "zipf",
+        }
+    }
+
+    /// Draw `DRAWS` keys in `0..KEYS` for each of `threads` threads.
+    fn draw(self, threads: usize) -> Vec<Vec<u64>> {
+        let cdf = match self {
+            Distribution::Uniform => Vec::new(),
+            Distribution::Zipf => {
+                let mut sum = 0.;
+                let mut cdf = (0..KEYS)
+                    .map(|k| {
+                        sum += 1. / (k + 1) as f64;
+                        sum
+                    })
+                    .collect::<Vec<_>>();
+                cdf.iter_mut().for_each(|p| *p /= sum);
+                cdf
+            }
+        };
+
+        (0..threads)
+            .map(|t| {
+                let mut rng = Rng::new(t);
+                (0..DRAWS)
+                    .map(|_| match self {
+                        Distribution::Uniform => rng.next() % KEYS,
+                        Distribution::Zipf => {
+                            let u = rng.unit();
+                            cdf.partition_point(|&p| p < u).min(KEYS as usize - 1) as u64
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Get,
+    Insert,
+    Remove,
+}
+
+/// Proportions of gets, inserts and removes, in percents.
+const WORKLOADS: &[(&str, [u64; 3])] = &[
+    ("read_heavy", [98, 1, 1]),
+    ("mixed", [50, 25, 25]),
+    ("write_heavy", [10, 45, 45]),
+];
+
+fn draw_ops(mix: [u64; 3], threads: usize) -> Vec<Vec<Op>> {
+    (0..threads)
+        .map(|t| {
+            let mut rng = Rng::new(threads + t);
+            (0..DRAWS)
+                .map(|_| match rng.next() % 100 {
+                    p if p < mix[0] => Op::Get,
+                    p if p < mix[0] + mix[1] => Op::Insert,
+                    _ => Op::Remove,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn thread_counts() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut counts = (0..)
+        .map(|i| 1 << i)
+        .take_while(|&n| n < cores)
+        .collect::<Vec<_>>();
+    counts.push(cores);
+    counts
+}
+
+/// Run `f(t, i)` for `i` in `0..iters` on each thread `t` of `threads`, and
+/// return the time taken by the slowest thread.
+fn run_threads<F: Fn(usize, usize) + Sync>(threads: usize, iters: u64, f: F) -> Duration {
+    let barrier = Barrier::new(threads);
+    std::thread::scope(|s| {
+        let handles = (0..threads)
+            .map(|t| {
+                let (barrier, f) = (&barrier, &f);
+                s.spawn(move || {
+                    barrier.wait();
+                    let start = Instant::now();
+                    for i in 0..iters as usize {
+                        f(t, i);
+                    }
+                    start.elapsed()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .max()
+            .unwrap()
+    })
+}
+
+fn filled<M: Map>() -> M {
+    let map = M::with_capacity(KEYS as usize);
+    for key in 0..KEYS {
+        map.insert(key, key);
+    }
+    map
+}
+
+/// Call `$f::<M>` with the given arguments for each of the compared maps.
+macro_rules! for_each_map {
+    ($f:ident($($arg:expr),*)) => {{
+        $f::<HashMap<u64, u64>>($($arg),*);
+        $f::<Mutex<StdMap>>($($arg),*);
+        $f::<RwLock<StdMap>>($($arg),*);
+        $f::<Sharded>($($arg),*);
+    }};
+}
+
+fn bench_workload<M: Map>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    threads: usize,
+    keys: &[Vec<u64>],
+    ops: &[Vec<Op>],
+) {
+    let map = filled::<M>();
+    group.bench_function(BenchmarkId::new(M::NAME, threads), |b| {
+        b.iter_custom(|iters| {
+            run_threads(threads, iters, |t, i| {
+                let key = keys[t][i % DRAWS];
+                match ops[t][i % DRAWS] {
+                    Op::Get => {
+                        black_box(map.get(key));
+                    }
+                    Op::Insert => map.insert(key, key),
+                    Op::Remove => map.remove(key),
+                }
+            })
+        })
+    });
+}
+
+fn workloads(c: &mut Criterion) {
+    for &(workload, mix) in WORKLOADS {
+        for distribution in [Distribution::Uniform, Distribution::Zipf] {
+            let mut group = c.benchmark_group(format!("{}/{}", workload, distribution.name()));
+            for threads in thread_counts() {
+                let (keys, ops) = (distribution.draw(threads), draw_ops(mix, threads));
+                group.throughput(Throughput::Elements(threads as u64));
+                for_each_map!(bench_workload(&mut group, threads, &keys, &ops));
+            }
+            group.finish();
+        }
+    }
+}
+
+fn bench_lookups<M: Map>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    threads: usize,
+    keys: &[Vec<u64>],
+    offset: u64,
+) {
+    let map = filled::<M>();
+    group.bench_function(BenchmarkId::new(M::NAME, threads), |b| {
+        b.iter_custom(|iters| {
+            run_threads(threads, iters, |t, i| {
+                black_box(map.get(keys[t][i % DRAWS] + offset));
+            })
+        })
+    });
+}
+
+/// Lookups only, of keys which are all in the maps or all missing.
+fn lookups(c: &mut Criterion) {
+    for (name, offset) in [("get/hit", 0), ("get/miss", KEYS)] {
+        let mut group = c.benchmark_group(name);
+        for threads in thread_counts() {
+            let keys = Distribution::Uniform.draw(threads);
+            group.throughput(Throughput::Elements(threads as u64));
+            for_each_map!(bench_lookups(&mut group, threads, &keys, offset));
+        }
+        group.finish();
+    }
+}
+
+fn bench_growth<M: Map>(group: &mut BenchmarkGroup<'_, WallTime>, threads: usize, capacity: usize) {
+    group.bench_function(BenchmarkId::new(M::NAME, threads), |b| {
+        b.iter_custom(|iters| {
+            let per_thread = KEYS as usize / threads;
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let map = M::with_capacity(capacity);
+                elapsed += run_threads(threads, per_thread as u64, |t, i| {
+                    let key = (t * per_thread + i) as u64;
+                    map.insert(key, key);
+                });
+            }
+            elapsed
+        })
+    });
+}
+
+/// Insert `KEYS` distinct keys into new maps, split across threads, either
+/// growing them from empty or with their capacity reserved upfront.
+fn growth(c: &mut Criterion) {
+    for (name, capacity) in [("grow/empty", 0), ("grow/presized", KEYS as usize)] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(KEYS));
+        for threads in thread_counts() {
+            for_each_map!(bench_growth(&mut group, threads, capacity));
+        }
+        group.finish();
+    }
+}
+
+fn config() -> Criterion {
+    Criterion::default()
+        .sample_size(20)
+        .measurement_time(Duration::from_secs(2))
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = workloads, lookups, growth
+}
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+//! Lookup benchmarks exercising bucket probing. Run once as is and once with
+//! `--features scalar-probe` to compare the vectorized and scalar paths.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use hash_map::hash_map::HashMap;
+use std::hint::black_box;
+
+const SIZES: &[u64] = &[1 << 10, 1 << 16];
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for &size in SIZES {
+        let map = HashMap::new();
+        for i in 0..size {
+            map.insert(i, i);
+        }
+
+        group.bench_with_input(BenchmarkId::new("hit", size), &size, |b, &size| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % size;
+                black_box(map.get(&i))
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("miss", size), &size, |b, &size| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % size;
+                black_box(map.get(&(i + size)))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+//! Drive sequences of `Arc`, `Weak`, `AtomicArc` and `AtomicWeak` operations
+//! over a few slots, checking loads against a model of the slots and that
+//! every value is dropped exactly once when all the slots are gone. Run with
+//! `cargo +nightly fuzz run atomic_arc`.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use hash_map::atomic_arc::{Arc, AtomicWeak, NullableAtomicArc, Weak};
+use libfuzzer_sys::fuzz_target;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SLOTS: usize = 4;
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// A value counting its drops. The canary is overwritten on drop, so that
+/// using a dropped value is caught even without a sanitizer.
+struct Tracked {
+    value: u8,
+    canary: u64,
+}
+
+const ALIVE: u64 = 0x5afe_5afe_5afe_5afe;
+
+impl Tracked {
+    fn new(value: u8) -> Arc<Tracked> {
+        CREATED.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Tracked {
+            value,
+            canary: ALIVE,
+        })
+    }
+
+    fn get(&self) -> u8 {
+        assert_eq!(self.canary, ALIVE, "use after drop");
+        self.value
+    }
+}
+
+impl Clone for Tracked {
+    fn clone(&self) -> Self {
+        CREATED.fetch_add(1, Ordering::Relaxed);
+        Tracked {
+            value: self.get(),
+            canary: ALIVE,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        assert_eq!(self.canary, ALIVE, "double drop");
+        self.canary = 0;
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    New { local: u8, value: u8 },
+    Clone { from: u8, to: u8 },
+    Drop { local: u8 },
+    MakeMut { local: u8, value: u8 },
+    Downgrade { local: u8, weak: u8 },
+    Upgrade { weak: u8, local: u8 },
+    Load { atomic: u8, local: u8 },
+    LoadGuard { atomic: u8 },
+    Store { atomic: u8, local: u8 },
+    Swap { atomic: u8, local: u8 },
+    Take { atomic: u8, local: u8 },
+    CompareExchange { atomic: u8, current: u8, new: u8 },
+    GetMut { atomic: u8, value: u8 },
+    StoreWeak { weak: u8 },
+    LoadWeak { weak: u8 },
+}
+
+fn read(arc: &Option<Arc<Tracked>>) -> Option<u8> {
+    arc.as_ref().map(|arc| arc.get())
+}
+
+fn run(ops: Vec<Op>) {
+    let mut locals: [Option<Arc<Tracked>>; SLOTS] = Default::default();
+    let mut weaks: [Option<Weak<Tracked>>; SLOTS] = Default::default();
+    let mut atomics: [NullableAtomicArc<Tracked>; SLOTS] = Default::default();
+    // The values the atomics are expected to hold.
+    let mut model = [None; SLOTS];
+    let atomic_weak = AtomicWeak::new_weak(Arc::downgrade(&Tracked::new(0)));
+
+    let slot = |i: u8| i as usize % SLOTS;
+    for op in ops {
+        match op {
+            Op::New { local, value } => locals[slot(local)] = Some(Tracked::new(value)),
+            Op::Clone { from, to } => locals[slot(to)] = locals[slot(from)].clone(),
+            Op::Drop { local } => locals[slot(local)] = None,
+            Op::MakeMut { local, value } => {
+                // Shared values are cloned, which the atomics must not see.
+                if let Some(arc) = &mut locals[slot(local)] {
+                    Arc::make_mut(arc).value = value;
+                    for (atomic, model) in atomics.iter().zip(model.iter()) {
+                        assert_eq!(read(&atomic.load()), *model);
+                    }
+                }
+            }
+            Op::Downgrade { local, weak } => {
+                weaks[slot(weak)] = locals[slot(local)].as_ref().map(Arc::downgrade)
+            }
+            Op::Upgrade { weak, local } => {
+                if let Some(weak) = &weaks[slot(weak)] {
+                    locals[slot(local)] = weak.upgrade();
+                }
+            }
+            Op::Load { atomic, local } => {
+                locals[slot(local)] = atomics[slot(atomic)].load();
+                assert_eq!(read(&locals[slot(local)]), model[slot(atomic)]);
+            }
+            Op::LoadGuard { atomic } => {
+                let guard = atomics[slot(atomic)].load_guard();
+                assert_eq!(guard.map(|guard| guard.get()), model[slot(atomic)]);
+            }
+            Op::Store { atomic, local } => {
+                let arc = locals[slot(local)].clone();
+                model[slot(atomic)] = read(&arc);
+                atomics[slot(atomic)].store(arc);
+            }
+            Op::Swap { atomic, local } => {
+                let arc = locals[slot(local)].take();
+                model[slot(atomic)] = read(&arc);
+                locals[slot(local)] = atomics[slot(atomic)].swap(arc);
+            }
+            Op::Take { atomic, local } => {
+                locals[slot(local)] = atomics[slot(atomic)].take();
+                assert_eq!(read(&locals[slot(local)]), model[slot(atomic)]);
+                model[slot(atomic)] = None;
+            }
+            Op::CompareExchange {
+                atomic,
+                current,
+                new,
+            } => {
+                let (current, new) = (&locals[slot(current)], locals[slot(new)].clone());
+                let expected = read(&new);
+                let stored = atomics[slot(atomic)].load();
+                let is_current = match (&stored, current) {
+                    (Some(stored), Some(current)) => Arc::ptr_eq(stored, current),
+                    (None, None) => true,
+                    _ => false,
+                };
+                drop(stored);
+                let result = atomics[slot(atomic)].compare_exchange(current, new);
+                assert_eq!(result.is_ok(), is_current);
+                if is_current {
+                    model[slot(atomic)] = expected;
+                }
+            }
+            Op::GetMut { atomic, value } => {
+                if let Some(tracked) = atomics[slot(atomic)].get_mut() {
+                    tracked.value = value;
+                    model[slot(atomic)] = Some(value);
+                }
+            }
+            Op::StoreWeak { weak } => {
+                if let Some(weak) = weaks[slot(weak)].clone() {
+                    atomic_weak.store(weak);
+                }
+            }
+            Op::LoadWeak { weak } => weaks[slot(weak)] = Some(atomic_weak.load()),
+        }
+        assert!(DROPPED.load(Ordering::Relaxed) <= CREATED.load(Ordering::Relaxed));
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    run(ops);
+    assert_eq!(
+        DROPPED.load(Ordering::Relaxed),
+        CREATED.load(Ordering::Relaxed),
+        "leaked values"
+    );
+});
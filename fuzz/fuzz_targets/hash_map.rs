@@ -0,0 +1,162 @@
+//! Drive sequences of `HashMap` operations, checking reads against
+//! `std::collections::HashMap` and that every value is dropped exactly once
+//! when the map and the values read from it are gone. Run with
+//! `cargo +nightly fuzz run hash_map`.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use hash_map::atomic_arc::Arc;
+use hash_map::hash_map::HashMap;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap as StdHashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// A value counting its drops, see the `atomic_arc` target.
+struct Tracked {
+    value: u16,
+    canary: u64,
+}
+
+const ALIVE: u64 = 0x5afe_5afe_5afe_5afe;
+
+impl Tracked {
+    fn new(value: u16) -> Tracked {
+        CREATED.fetch_add(1, Ordering::Relaxed);
+        Tracked {
+            value,
+            canary: ALIVE,
+        }
+    }
+
+    fn get(&self) -> u16 {
+        assert_eq!(self.canary, ALIVE, "use after drop");
+        self.value
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        assert_eq!(self.canary, ALIVE, "double drop");
+        self.canary = 0;
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(u8, u16),
+    Get(u8),
+    Keep(u8),
+    Remove(u8),
+    Reserve(u8),
+    InsertMany(Vec<(u8, u16)>),
+    GetMany(Vec<u8>),
+    RemoveMany(Vec<u8>),
+    RawInsert(u8, u16),
+    RawGet(u8),
+    RawRemove(u8),
+}
+
+/// Keys of the raw operations, which all share a bucket and its chained
+/// buckets. They are out of the range of the other keys, which would not
+/// find them.
+fn raw_key(key: u8) -> u16 {
+    256 + key as u16
+}
+
+const RAW_HASH: u64 = 42;
+
+fn run(ops: Vec<Op>) {
+    let map = HashMap::new();
+    let mut model = StdHashMap::<u16, u16>::new();
+    let mut raw_model = StdHashMap::new();
+    // Values read from the map, which must outlive it.
+    let mut kept = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => {
+                map.insert(key as u16, Tracked::new(value));
+                model.insert(key as u16, value);
+            }
+            Op::Get(key) => {
+                assert_eq!(
+                    map.get(&(key as u16)).map(|v| v.get()),
+                    model.get(&(key as u16)).copied()
+                );
+            }
+            Op::Keep(key) => kept.extend(map.get(&(key as u16))),
+            Op::Remove(key) => {
+                map.remove(&(key as u16));
+                model.remove(&(key as u16));
+            }
+            Op::Reserve(additional) => map.reserve(additional as usize),
+            Op::InsertMany(items) => {
+                map.insert_many(items.iter().map(|&(k, v)| (k as u16, Tracked::new(v))));
+                model.extend(items.iter().map(|&(k, v)| (k as u16, v)));
+            }
+            Op::GetMany(keys) => {
+                let keys = keys.into_iter().map(u16::from).collect::<Vec<_>>();
+                let values = map.get_many(&keys);
+                for (key, value) in keys.iter().zip(values) {
+                    assert_eq!(value.map(|v| v.get()), model.get(key).copied());
+                }
+            }
+            Op::RemoveMany(keys) => {
+                let keys = keys.into_iter().map(u16::from).collect::<Vec<_>>();
+                map.remove_many(&keys);
+                for key in keys {
+                    model.remove(&key);
+                }
+            }
+            Op::RawInsert(key, value) => {
+                map.raw_insert(RAW_HASH, raw_key(key), Tracked::new(value));
+                raw_model.insert(raw_key(key), value);
+            }
+            Op::RawGet(key) => {
+                let value = map.raw_get(RAW_HASH, |k| *k == raw_key(key));
+                assert_eq!(
+                    value.map(|v| v.get()),
+                    raw_model.get(&raw_key(key)).copied()
+                );
+            }
+            Op::RawRemove(key) => {
+                map.raw_remove(RAW_HASH, |k| *k == raw_key(key));
+                raw_model.remove(&raw_key(key));
+            }
+        }
+        assert!(DROPPED.load(Ordering::Relaxed) <= CREATED.load(Ordering::Relaxed));
+    }
+
+    for key in 0..=u8::MAX {
+        let value = map.get(&(key as u16));
+        assert_eq!(
+            value.as_ref().map(|v| v.get()),
+            model.get(&(key as u16)).copied()
+        );
+        kept.extend(value);
+        let value = map.raw_get(RAW_HASH, |k| *k == raw_key(key));
+        assert_eq!(
+            value.as_ref().map(|v| v.get()),
+            raw_model.get(&raw_key(key)).copied()
+        );
+        kept.extend(value);
+    }
+    drop(map);
+    for value in &kept {
+        value.get();
+    }
+    drop::<Vec<Arc<Tracked>>>(kept);
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    run(ops);
+    assert_eq!(
+        DROPPED.load(Ordering::Relaxed),
+        CREATED.load(Ordering::Relaxed),
+        "leaked values"
+    );
+});
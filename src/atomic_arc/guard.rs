@@ -0,0 +1,93 @@
+use super::inner::{Arc, Inner};
+use super::packed::{self, AtomicWord};
+use crate::sync::Ordering;
+use allocator_api2::alloc::{Allocator, Global};
+use core::ptr::NonNull;
+
+/// A reference to the contents of an [AtomicArc<T>](super::AtomicArc),
+/// borrowing the `AtomicArc<T>` it was loaded from.
+///
+/// A loaded value is accounted for in the outer count of the `AtomicArc<T>`.
+/// When a guard is dropped and the `AtomicArc<T>` still holds the same
+/// value, the guard simply decrements that outer count, which lives on a
+/// cache line that was just touched by the load. It only falls back to
+/// releasing the inner count, like an `Arc<T>` would, if the contents of the
+/// `AtomicArc<T>` changed in the meantime.
+pub struct Guard<'a, T: ?Sized, A: Allocator = Global> {
+    inner: NonNull<Inner<T, A>>,
+    ptr_and_count: &'a AtomicWord,
+    _phantom: core::marker::PhantomData<&'a T>,
+}
+
+impl<'a, T: ?Sized, A: Allocator> Guard<'a, T, A> {
+    /// Safety: `inner` must point to a valid `Inner<T>`, and the caller must
+    /// transfer to the guard a reference it owns on it.
+    pub(super) unsafe fn new(inner: *mut Inner<T, A>, ptr_and_count: &'a AtomicWord) -> Self {
+        Self {
+            inner: NonNull::new_unchecked(inner),
+            ptr_and_count,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// Return a new `Arc<T>` pointing to the same value as `guard`.
+    pub fn upgrade(guard: &Self) -> Arc<T, A> {
+        unsafe {
+            guard.inner.as_ref().counts.basic_acquire(1);
+            Arc::from_inner(guard.inner.as_ptr())
+        }
+    }
+
+    /// Convert `guard` into an `Arc<T>`, without touching any count.
+    pub fn into_arc(guard: Self) -> Arc<T, A> {
+        let inner = guard.inner.as_ptr();
+        core::mem::forget(guard);
+        unsafe { Arc::from_inner(inner) }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> core::ops::Deref for Guard<'_, T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &self.inner.as_ref().value }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Guard<'_, T, A> {
+    fn drop(&mut self) {
+        // Outer counts are interchangeable as long as they refer to the same
+        // pointee, which cannot be reused while we hold a reference on it.
+        // Guards are only loaded from unmarked words.
+        let ptr = self.inner.as_ptr() as *mut u8;
+        let mut ptr_and_count = self.ptr_and_count.load(Ordering::Relaxed);
+        while packed::ptr(ptr_and_count) == ptr && packed::count(ptr_and_count, false) > 0 {
+            match self.ptr_and_count.compare_exchange_weak(
+                ptr_and_count,
+                packed::decrement(ptr_and_count),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return,
+                Err(actual) => ptr_and_count = actual,
+            }
+        }
+
+        unsafe { Inner::release(self.inner.as_ptr(), 1, 0) }
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Send for Guard<'_, T, A> {}
+unsafe impl<T: ?Sized + Sync, A: Allocator + Sync> Sync for Guard<'_, T, A> {}
+
+impl<T: ?Sized + core::fmt::Debug, A: Allocator> core::fmt::Debug for Guard<'_, T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> From<Guard<'_, T, A>> for Arc<T, A> {
+    fn from(guard: Guard<'_, T, A>) -> Self {
+        Guard::into_arc(guard)
+    }
+}
@@ -1,99 +1,587 @@
-use std::ptr::NonNull;
-use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use super::abort;
+use crate::sync::{fence, spin_loop, AtomicU64, Ordering};
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use allocator_api2::alloc::{Allocator, Global};
+use core::mem::ManuallyDrop;
+use core::ptr::NonNull;
 
-const BASIC_COUNT_SHIFT: usize = 32;
-const STRONG_COUNT_MASK: usize = (-1isize as usize) >> 32;
-const MAX_BASIC_COUNT: i32 = std::i32::MAX as _;
-const MIN_BASIC_COUNT: i32 = std::i32::MIN as _;
-const MAX_STRONG_COUNT: usize = std::u32::MAX as _;
+const BASIC_COUNT_SHIFT: u32 = 32;
+const STRONG_COUNT_MASK: u64 = u32::MAX as _;
+const MAX_BASIC_COUNT: i32 = i32::MAX as _;
+const MIN_BASIC_COUNT: i32 = i32::MIN as _;
+const MAX_STRONG_COUNT: u64 = u32::MAX as _;
+/// Neither a valid strong count nor a valid basic count.
+const LOCKED_COUNTS: u64 = u64::MAX;
+
+/// A basic count and a strong count packed on one 64-bit word:
+/// bits: 63------32|31------0
+/// data:   basic   |  strong
+///
+/// The basic count tracks owned references, e.g. `Arc<T>`, and the strong
+/// count tracks `AtomicArc<T>` cells holding the pointer, whose own outer
+/// counts are only transferred to the basic count once they let go of it.
+/// Hence the basic count is signed, and the counts are only dead once both
+/// are zero.
+///
+/// We abort the process if we overflow one of the two counts, but this can
+/// only happen if purposefully abusing `core::mem::forget` or other ways of
+/// massively leaking references (basic count overflow) or atomic cells
+/// (strong count overflow). Note that the basic count can also underflow,
+/// which we'll check as well.
+pub(super) struct Counts(AtomicU64);
+
+impl Counts {
+    fn new(basic: i32) -> Self {
+        Counts(AtomicU64::new((basic as u64) << BASIC_COUNT_SHIFT))
+    }
 
-#[repr(align(16))]
-/// We abort the process if we overflow one of the two inner counts, but this
-/// can only happen if purposefully abusing `std::mem::forget` or other ways
-/// of massively leaking `Arc<T>` (basic count overflow) or `AtomicArc<T>`
-/// (strong count overflow). Note that the basic count is signed and can
-/// underflow, which we'll check as well.
-pub(super) struct Inner<T> {
-    // We pack both inner counts on one word:
-    // bits: 63------32|31-----0
-    // data:   strong  |  basic
-    counts: AtomicUsize,
-    value: T,
-}
-
-impl<T> Inner<T> {
     pub(super) fn strong_acquire(&self) {
-        let old_counts = self.counts.fetch_add(1, Ordering::Relaxed);
+        let old_counts = self.0.fetch_add(1, Ordering::Relaxed);
         if old_counts & STRONG_COUNT_MASK == MAX_STRONG_COUNT {
-            std::process::abort();
+            abort();
         }
     }
 
-    /// Safety: `inner` must point to a valid `Inner<T>`, and `strong` must not
-    /// cause the strong count to underflow.
-    pub(super) unsafe fn release(inner: *mut Inner<T>, basic: i32, strong: usize) {
-        // Atomically substract `strong` to the strong count and `basic` to the
-        // basic count. We rely on the carry on the highest bit being discarded.
-        let old_counts = (*inner).counts.fetch_sub(
-            strong | ((basic as usize) << BASIC_COUNT_SHIFT),
+    /// Add `basic` to the basic count.
+    pub(super) fn basic_acquire(&self, basic: i32) {
+        let old_counts = self
+            .0
+            .fetch_add((basic as u64) << BASIC_COUNT_SHIFT, Ordering::Relaxed);
+        if (old_counts >> BASIC_COUNT_SHIFT) as i32 > MAX_BASIC_COUNT - basic {
+            abort();
+        }
+    }
+
+    /// Increment the basic count, waiting for the counts to be unlocked if
+    /// needed, see `Counts::lock`.
+    fn basic_acquire_unlocked(&self) {
+        let mut counts = self.0.load(Ordering::Relaxed);
+        loop {
+            if counts == LOCKED_COUNTS {
+                spin_loop();
+                counts = self.0.load(Ordering::Relaxed);
+                continue;
+            } else if (counts >> BASIC_COUNT_SHIFT) as i32 == MAX_BASIC_COUNT {
+                abort();
+            }
+
+            match self.0.compare_exchange_weak(
+                counts,
+                counts.wrapping_add(1 << BASIC_COUNT_SHIFT),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return,
+                Err(actual) => counts = actual,
+            }
+        }
+    }
+
+    /// Return whether the counts are exactly `basic` and `strong`.
+    fn is(&self, basic: i32, strong: usize) -> bool {
+        self.0.load(Ordering::Acquire) == ((basic as u64) << BASIC_COUNT_SHIFT) | strong as u64
+    }
+
+    /// Lock the counts if they are exactly one basic count, so that they
+    /// cannot be incremented through `basic_acquire_unlocked` until they are
+    /// unlocked. Return whether we succeeded.
+    fn lock(&self) -> bool {
+        self.0
+            .compare_exchange(
+                1 << BASIC_COUNT_SHIFT,
+                LOCKED_COUNTS,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    fn unlock(&self) {
+        self.0.store(1 << BASIC_COUNT_SHIFT, Ordering::Release);
+    }
+
+    /// Kill the counts if they are exactly one basic count. Return whether
+    /// we succeeded.
+    fn kill_unique(&self) -> bool {
+        self.0
+            .compare_exchange(
+                1 << BASIC_COUNT_SHIFT,
+                0,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Return the sum of both counts, which is at least zero.
+    fn sum(&self) -> usize {
+        let counts = self.0.load(Ordering::Relaxed);
+        let basic = (counts >> BASIC_COUNT_SHIFT) as i32 as i64;
+        core::cmp::max(basic + (counts & STRONG_COUNT_MASK) as i64, 0) as usize
+    }
+
+    /// Increment the basic count, unless both counts are already zero, in
+    /// which case they will stay so forever. Return whether we succeeded.
+    fn basic_acquire_if_alive(&self) -> bool {
+        let mut counts = self.0.load(Ordering::Relaxed);
+        loop {
+            if counts == 0 {
+                return false;
+            } else if (counts >> BASIC_COUNT_SHIFT) as i32 == MAX_BASIC_COUNT {
+                abort();
+            }
+
+            match self.0.compare_exchange_weak(
+                counts,
+                counts.wrapping_add(1 << BASIC_COUNT_SHIFT),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return true,
+                Err(actual) => counts = actual,
+            }
+        }
+    }
+
+    /// Substract `basic` to the basic count and `strong` to the strong count,
+    /// and return whether both counts reached zero. `strong` must not cause
+    /// the strong count to underflow.
+    fn release(&self, basic: i32, strong: usize) -> bool {
+        // We rely on the carry on the highest bit being discarded.
+        let old_counts = self.0.fetch_sub(
+            strong as u64 | ((basic as u64) << BASIC_COUNT_SHIFT),
             Ordering::Release,
         );
         let old_basic = (old_counts >> BASIC_COUNT_SHIFT) as i32;
         let old_strong = old_counts & STRONG_COUNT_MASK;
-        if old_basic > MAX_BASIC_COUNT + std::cmp::min(basic, 0) {
-            std::process::abort();
-        } else if old_basic < MIN_BASIC_COUNT + std::cmp::max(basic, 0) {
-            std::process::abort();
-        } else if old_basic == basic && old_strong == strong {
+        if old_basic > MAX_BASIC_COUNT + core::cmp::min(basic, 0)
+            || old_basic < MIN_BASIC_COUNT + core::cmp::max(basic, 0)
+        {
+            abort();
+        } else if old_basic == basic && old_strong == strong as u64 {
             fence(Ordering::Acquire);
-            Box::from_raw(inner);
+            true
+        } else {
+            false
         }
     }
 }
 
+#[repr(C)]
+#[repr(align(16))]
+/// The value is dropped once `counts` are dead, and the allocation is freed
+/// once `weak_counts` are dead. Weak references are counted in `weak_counts`
+/// the same way references are counted in `counts`, so that they can be
+/// stored in an [AtomicWeak<T>](super::AtomicWeak). All the references to
+/// the value collectively own one basic weak count.
+///
+/// `layout` is the layout of the whole allocation, computed while the value
+/// is alive: it cannot be computed from a dropped value. The allocator is
+/// stored in the allocation as well, since it must be reachable from any
+/// reference, including from an `AtomicArc<T>`.
+///
+/// `AtomicArc<T>` only stores the address of an `Inner<T>`, so when `T` is
+/// unsized, e.g. `str`, `[U]` or `dyn Trait`, we need another way to recover
+/// the metadata of the pointer: the allocation then starts with a prefix
+/// holding the full pointer right before the `Inner<T>`, which we read back
+/// through `Inner::from_thin`. `Inner<T>` is `repr(C)` so that we can lay it
+/// out manually for unsized values.
+pub(super) struct Inner<T: ?Sized, A: Allocator = Global> {
+    pub(super) counts: Counts,
+    pub(super) weak_counts: Counts,
+    layout: Layout,
+    alloc: A,
+    pub(super) value: ManuallyDrop<T>,
+}
+
+impl<T: ?Sized, A: Allocator> Inner<T, A> {
+    /// Return whether pointers to an `Inner<T>` are wide, in which case the
+    /// allocation starts with a prefix holding one.
+    fn is_wide() -> bool {
+        core::mem::size_of::<*mut Inner<T, A>>() != core::mem::size_of::<*mut u8>()
+    }
+
+    /// Return the offset of the `Inner<T>` in an allocation of `layout`.
+    fn prefix_size(layout: Layout) -> usize {
+        if Self::is_wide() {
+            core::mem::size_of::<*mut Inner<T, A>>().next_multiple_of(layout.align())
+        } else {
+            0
+        }
+    }
+
+    /// Return the layout of an `Inner<T>` whose value has the layout `value`,
+    /// the same way `repr(C)` lays it out.
+    fn layout_for(value: Layout) -> Layout {
+        Layout::from_size_align(0, 16)
+            .and_then(|layout| layout.extend(Layout::new::<Counts>()))
+            .and_then(|(layout, _)| layout.extend(Layout::new::<Counts>()))
+            .and_then(|(layout, _)| layout.extend(Layout::new::<Layout>()))
+            .and_then(|(layout, _)| layout.extend(Layout::new::<A>()))
+            .and_then(|(layout, _)| layout.extend(value))
+            .unwrap()
+            .0
+            .pad_to_align()
+    }
+
+    /// Allocate room for an `Inner<T>` of layout `inner` in `alloc`, and its
+    /// prefix if needed. Return the layout of the allocation and a pointer to
+    /// the `Inner<T>` in it.
+    fn allocate(inner: Layout, alloc: &A) -> (Layout, *mut u8) {
+        let (layout, offset) = if Self::is_wide() {
+            Layout::new::<*mut Inner<T, A>>().extend(inner).unwrap()
+        } else {
+            (inner, 0)
+        };
+        let layout = layout.pad_to_align();
+        debug_assert_eq!(offset, Self::prefix_size(layout));
+
+        let data = match alloc.allocate(layout) {
+            Ok(data) => data.as_ptr() as *mut u8,
+            Err(..) => alloc::alloc::handle_alloc_error(layout),
+        };
+        let data = unsafe { data.add(offset) };
+        check_ptr(data);
+        (layout, data)
+    }
+
+    /// Initialize everything but the value of an `Inner<T>` returned by
+    /// `Inner::allocate` with the layout `layout`, for one basic count.
+    ///
+    /// Safety: `inner` must come from `Inner::allocate`, with the provenance
+    /// of the whole allocation.
+    unsafe fn init(inner: *mut Inner<T, A>, layout: Layout, alloc: A) {
+        core::ptr::addr_of_mut!((*inner).counts).write(Counts::new(1));
+        core::ptr::addr_of_mut!((*inner).weak_counts).write(Counts::new(1));
+        core::ptr::addr_of_mut!((*inner).layout).write(layout);
+        core::ptr::addr_of_mut!((*inner).alloc).write(alloc);
+        if Self::is_wide() {
+            let prefix = (inner as *mut u8).sub(core::mem::size_of::<*mut Inner<T, A>>());
+            (prefix as *mut *mut Inner<T, A>).write(inner);
+        }
+    }
+
+    /// Return a pointer to the `Inner<T>` that `thin` points to, i.e. add
+    /// back its metadata.
+    ///
+    /// Safety: `thin` must point to a valid `Inner<T>`.
+    pub(super) unsafe fn from_thin(thin: *mut u8) -> *mut Inner<T, A> {
+        if Self::is_wide() {
+            let prefix = thin.sub(core::mem::size_of::<*mut Inner<T, A>>());
+            *(prefix as *const *mut Inner<T, A>)
+        } else {
+            core::mem::transmute_copy(&thin)
+        }
+    }
+
+    /// Return whether `counts` are exactly `basic` and `strong`, and nobody
+    /// holds a weak reference to the value, so that the caller owning these
+    /// counts has exclusive access to it.
+    pub(super) fn is_unique(&self, basic: i32, strong: usize) -> bool {
+        // Lock the weak counts first so that no other reference can be
+        // downgraded while we check the counts. We do not need to lock the
+        // counts: if they show that we are unique, nobody can increment them.
+        if !self.weak_counts.lock() {
+            return false;
+        }
+        let unique = self.counts.is(basic, strong);
+        self.weak_counts.unlock();
+        unique
+    }
+
+    /// Safety: `inner` must point to a valid `Inner<T>`, and `strong` must not
+    /// cause the strong count to underflow.
+    pub(super) unsafe fn release(inner: *mut Inner<T, A>, basic: i32, strong: usize) {
+        if (*inner).counts.release(basic, strong) {
+            ManuallyDrop::drop(&mut (*inner).value);
+            Inner::weak_release(inner, 1, 0);
+        }
+    }
+
+    /// Same as `Inner::release`, for weak counts. The value must already be
+    /// dropped, so we do not make references to the whole `Inner<T>`.
+    pub(super) unsafe fn weak_release(inner: *mut Inner<T, A>, basic: i32, strong: usize) {
+        if (*inner).weak_counts.release(basic, strong) {
+            let layout = (*inner).layout;
+            let alloc = core::ptr::read(&(*inner).alloc);
+            let data = (inner as *mut u8).sub(Self::prefix_size(layout));
+            alloc.deallocate(NonNull::new_unchecked(data), layout);
+        }
+    }
+}
+
+impl<T, A: Allocator> Inner<T, A> {
+    /// Allocate a new `Inner<T>` with the given `counts` in `alloc`.
+    fn alloc(counts: i32, value: T, alloc: A) -> *mut Inner<T, A> {
+        let (layout, inner) = Self::allocate(Layout::new::<Inner<T, A>>(), &alloc);
+        let inner = inner as *mut Inner<T, A>;
+        unsafe {
+            core::ptr::write(
+                inner,
+                Inner {
+                    counts: Counts::new(counts),
+                    weak_counts: Counts::new(1),
+                    layout,
+                    alloc,
+                    value: ManuallyDrop::new(value),
+                },
+            );
+        }
+        inner
+    }
+}
+
 /// A thread-safe reference-counting pointer that can be stored in an
 /// [AtomicArc<T>](crate::atomic_arc::AtomicArc). An `Arc<T>` can be cheaply
 /// cloned.
-pub struct Arc<T> {
-    inner: NonNull<Inner<T>>,
-    _phantom: std::marker::PhantomData<T>,
+///
+/// The value is allocated with `A`, which defaults to the global allocator,
+/// see `Arc::new_in`.
+pub struct Arc<T: ?Sized, A: Allocator = Global> {
+    inner: NonNull<Inner<T, A>>,
+    _phantom: core::marker::PhantomData<T>,
 }
 
-fn check_ptr(ptr: usize) {
-    assert_eq!(ptr >> 48, 0);
-    assert_eq!(ptr % (1 << super::PTR_SHIFT), 0);
+fn check_ptr(ptr: *mut u8) {
+    assert!(
+        super::packed::is_packable(ptr),
+        "{:p} cannot be stored in an `AtomicArc<T>`, see the `wide-pointers` feature",
+        ptr
+    );
 }
 
 impl<T> Arc<T> {
     /// Return a new `Arc<T>`.
     pub fn new(value: T) -> Self {
-        let inner = Box::into_raw(Box::new(Inner {
-            counts: AtomicUsize::new(1 << BASIC_COUNT_SHIFT),
-            value,
-        }));
+        Self::new_in(value, Global)
+    }
 
-        check_ptr(inner as usize);
+    /// Return a new `Arc<T>` whose value is built by `data_fn` from a
+    /// `Weak<T>` pointing to it. Upgrading that `Weak<T>` fails until
+    /// `data_fn` returns.
+    pub fn new_cyclic<F: FnOnce(&Weak<T>) -> T>(data_fn: F) -> Self {
+        // Dead counts, and one basic weak count for the `Weak<T>` we hand
+        // out, which will become the weak count owned by the references.
+        let inner = Inner::alloc(0, core::mem::MaybeUninit::<T>::uninit(), Global) as *mut Inner<T>;
 
-        Self {
-            inner: unsafe { NonNull::new_unchecked(inner) },
-            _phantom: std::marker::PhantomData,
+        let weak = unsafe { Weak::from_inner(inner) };
+        let value = data_fn(&weak);
+        unsafe {
+            core::ptr::write(&mut (*inner).value, ManuallyDrop::new(value));
+            (*inner)
+                .counts
+                .0
+                .store(1 << BASIC_COUNT_SHIFT, Ordering::Release);
+        }
+        core::mem::forget(weak);
+
+        unsafe { Self::from_inner(inner) }
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
+    /// Return a new `Arc<T>` holding `value` as an unsized `T`, e.g. a trait
+    /// object, which `coerce` turns a pointer to `value` into.
+    ///
+    /// This is how trait objects are stored in an `Arc<T>`: on stable Rust,
+    /// the value of a `Box<dyn Trait>` cannot be moved to another allocation
+    /// without relying on the layout of wide pointers, so unlike
+    /// `std::sync::Arc` we do not implement `From<Box<T>>` for them.
+    ///
+    /// ```
+    /// # use hash_map::atomic_arc::Arc;
+    /// let debug: Arc<dyn std::fmt::Debug> = unsafe { Arc::new_unsized(5, |ptr| ptr as _) };
+    /// assert_eq!(format!("{:?}", debug), "5");
+    /// ```
+    ///
+    /// # Safety
+    /// `coerce` must return its argument, only unsized through a coercion,
+    /// e.g. `|ptr| ptr as *mut dyn Trait`.
+    pub unsafe fn new_unsized<U>(value: U, coerce: impl FnOnce(*mut U) -> *mut T) -> Self {
+        let (layout, data) = Inner::<T>::allocate(Layout::new::<Inner<U>>(), &Global);
+        let sized = core::ptr::addr_of_mut!((*(data as *mut Inner<U>)).value) as *mut U;
+        sized.write(value);
+
+        let value = coerce(sized);
+        debug_assert!(core::ptr::addr_eq(value, sized));
+        // `Inner<U>` and `Inner<T>` are laid out the same way, as the value
+        // of the latter has the size and alignment of `U`.
+        let inner = (value as *mut Inner<T>).byte_sub(core::mem::offset_of!(Inner<U>, value));
+        Inner::init(inner, layout, Global);
+        Self::from_inner(inner)
+    }
+}
+
+impl<T, A: Allocator> Arc<T, A> {
+    /// Return a new `Arc<T>` whose value is allocated with `alloc`.
+    pub fn new_in(value: T, alloc: A) -> Self {
+        unsafe { Self::from_inner(Inner::alloc(1, value, alloc)) }
+    }
+
+    /// Return a mutable reference to the value of `this`, cloning it into a
+    /// new `Arc<T>` first if other strong references point to it. If only
+    /// weak references do, the value is moved into a new `Arc<T>` instead,
+    /// and they can no longer be upgraded.
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        if !this.is_unique() {
+            if this.inner().counts.kill_unique() {
+                let inner = this.inner.as_ptr();
+                unsafe {
+                    let value = ManuallyDrop::take(&mut (*inner).value);
+                    let alloc = (*inner).alloc.clone();
+                    // The dead counts must not be released again.
+                    core::ptr::write(this, Arc::new_in(value, alloc));
+                    Inner::weak_release(inner, 1, 0);
+                }
+            } else {
+                *this = Arc::new_in((**this).clone(), this.inner().alloc.clone());
+            }
+        }
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+
+    /// Return the value of `this` if it is its only strong reference, or
+    /// `this` otherwise. Weak references do not prevent unwrapping.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if !this.inner().counts.kill_unique() {
+            return Err(this);
+        }
+
+        let inner = this.inner.as_ptr();
+        core::mem::forget(this);
+        unsafe {
+            let value = ManuallyDrop::take(&mut (*inner).value);
+            Inner::weak_release(inner, 1, 0);
+            Ok(value)
         }
     }
 
+    /// Release `this`, and return its value if it was the last strong
+    /// reference to it. Unlike `Arc::try_unwrap`, if several threads call
+    /// `into_inner` on the last references to a value, exactly one of them
+    /// gets it.
+    pub fn into_inner(this: Self) -> Option<T> {
+        let inner = this.inner.as_ptr();
+        core::mem::forget(this);
+        unsafe {
+            if (*inner).counts.release(1, 0) {
+                let value = ManuallyDrop::take(&mut (*inner).value);
+                Inner::weak_release(inner, 1, 0);
+                Some(value)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Consume `this` and return a pointer to its value. The pointer must be
+    /// passed to `Arc::from_raw` in order to release the reference.
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Arc::as_ptr(&this);
+        core::mem::forget(this);
+        ptr
+    }
+
+    /// Rebuild an `Arc<T, A>` from a pointer returned by `Arc::into_raw`.
+    /// Unlike `std::sync::Arc`, there is no `from_raw_in`: the allocator is
+    /// stored along with the value, and the pointer is enough to find it.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `Arc::into_raw` for the same `T` and
+    /// `A`, and each such pointer can only be rebuilt once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let offset = core::mem::offset_of!(Inner<T, A>, value);
+        Arc::from_inner((ptr as *const u8).sub(offset) as *mut Inner<T, A>)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Arc<T, A> {
     /// Safety: `inner` must point to a valid `Inner<T>`.
-    pub(super) unsafe fn from_inner(inner: *mut Inner<T>) -> Self {
+    pub(super) unsafe fn from_inner(inner: *mut Inner<T, A>) -> Self {
         Self {
             inner: NonNull::new_unchecked(inner),
-            _phantom: std::marker::PhantomData,
+            _phantom: core::marker::PhantomData,
         }
     }
 
-    pub(super) fn inner(&self) -> &Inner<T> {
+    pub(super) fn inner(&self) -> &Inner<T, A> {
         unsafe { self.inner.as_ref() }
     }
+
+    /// Return the pointer to the `Inner<T>` without its metadata, as stored
+    /// by an `AtomicArc<T>`.
+    pub(super) fn thin_ptr(this: &Self) -> *mut u8 {
+        this.inner.as_ptr() as *mut u8
+    }
+
+    /// Return a new `Weak<T>` pointing to the same value as `this`.
+    pub fn downgrade(this: &Self) -> Weak<T, A> {
+        this.inner().weak_counts.basic_acquire_unlocked();
+        unsafe { Weak::from_inner(this.inner.as_ptr()) }
+    }
+
+    /// Return whether `this` and `other` point to the same value.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        core::ptr::addr_eq(this.inner.as_ptr(), other.inner.as_ptr())
+    }
+
+    /// Return the number of `Arc<T>`s and `AtomicArc<T>`s pointing to the
+    /// value of `this`.
+    ///
+    /// # Notes
+    /// This is exact only if no `AtomicArc<T>` points to the value: an
+    /// `Arc<T>` loaded from an `AtomicArc<T>` is accounted for when the
+    /// `AtomicArc<T>` stops pointing to the value (or resets its outer
+    /// count), while dropping it is accounted for immediately. In any case,
+    /// the result is only a snapshot.
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().counts.sum()
+    }
+
+    /// Return the number of `Weak<T>`s and `AtomicWeak<T>`s pointing to the
+    /// value of `this`, with the same caveats as `Arc::strong_count`.
+    pub fn weak_count(this: &Self) -> usize {
+        this.inner().weak_counts.sum().saturating_sub(1)
+    }
+
+    /// Return whether `this` is the only reference to its value, including
+    /// weak ones. `AtomicArc<T>`s and `AtomicWeak<T>`s pointing to the value
+    /// hold a strong count, so they count as references as well.
+    fn is_unique(&mut self) -> bool {
+        self.inner().is_unique(1, 0)
+    }
+
+    /// Return a mutable reference to the value of `this` if it is its only
+    /// reference, see `Arc::is_unique`.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.is_unique() {
+            Some(unsafe { &mut (*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+
+    /// Return a pointer to the value of `this`.
+    pub fn as_ptr(this: &Self) -> *const T {
+        // Do not go through a reference, so that the pointer keeps the
+        // provenance of the whole `Inner<T>`, which `Arc::from_raw` needs.
+        unsafe { core::ptr::addr_of!((*this.inner.as_ptr()).value) as *const T }
+    }
+
+    /// Return the allocator of the value of `this`.
+    pub fn allocator(this: &Self) -> &A {
+        &this.inner().alloc
+    }
 }
 
-impl<T> std::ops::Deref for Arc<T> {
+impl<T: ?Sized, A: Allocator> core::ops::Deref for Arc<T, A> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -101,24 +589,18 @@ impl<T> std::ops::Deref for Arc<T> {
     }
 }
 
-impl<T> Clone for Arc<T> {
+impl<T: ?Sized, A: Allocator> Clone for Arc<T, A> {
     fn clone(&self) -> Self {
-        let old_counts = self
-            .inner()
-            .counts
-            .fetch_add(1 << BASIC_COUNT_SHIFT, Ordering::Relaxed);
-        if (old_counts >> BASIC_COUNT_SHIFT) as i32 == MAX_BASIC_COUNT {
-            std::process::abort();
-        }
+        self.inner().counts.basic_acquire(1);
 
         Self {
             inner: self.inner,
-            _phantom: std::marker::PhantomData,
+            _phantom: core::marker::PhantomData,
         }
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl<T: ?Sized, A: Allocator> Drop for Arc<T, A> {
     fn drop(&mut self) {
         unsafe {
             Inner::release(self.inner.as_ptr(), 1, 0);
@@ -126,11 +608,229 @@ impl<T> Drop for Arc<T> {
     }
 }
 
-unsafe impl<T: Send> Send for Arc<T> {}
-unsafe impl<T: Sync> Sync for Arc<T> {}
+// The value is shared by all the references and dropped by the last one,
+// on any thread, and so is the allocator.
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Send for Arc<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Sync for Arc<T, A> {}
+
+impl<T: ?Sized + core::fmt::Debug, A: Allocator> core::fmt::Debug for Arc<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + core::fmt::Display, A: Allocator> core::fmt::Display for Arc<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> core::fmt::Pointer for Arc<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Pointer::fmt(&Arc::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Arc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Arc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Arc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Arc<T, A> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + core::hash::Hash, A: Allocator> core::hash::Hash for Arc<T, A> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> core::borrow::Borrow<T> for Arc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Arc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(value: T) -> Self {
+        Arc::new(value)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl Default for Arc<str> {
+    fn default() -> Self {
+        Arc::from("")
+    }
+}
+
+impl<T> Default for Arc<[T]> {
+    fn default() -> Self {
+        Arc::from(Vec::new())
+    }
+}
+
+/// A weak reference to the value of an [Arc<T>](self::Arc), which does not
+/// keep the value alive, and which can be stored in an
+/// [AtomicWeak<T>](super::AtomicWeak).
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
+    inner: NonNull<Inner<T, A>>,
+    _phantom: core::marker::PhantomData<T>,
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    /// Safety: `inner` must point to a valid `Inner<T>`.
+    pub(super) unsafe fn from_inner(inner: *mut Inner<T, A>) -> Self {
+        Self {
+            inner: NonNull::new_unchecked(inner),
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    pub(super) fn inner(&self) -> &Inner<T, A> {
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// Return the pointer to the `Inner<T>` without its metadata, as stored
+    /// by an `AtomicArc<T>`.
+    pub(super) fn thin_ptr(this: &Self) -> *mut u8 {
+        this.inner.as_ptr() as *mut u8
+    }
+
+    /// Return an `Arc<T>` pointing to the value, if it is still alive.
+    pub fn upgrade(&self) -> Option<Arc<T, A>> {
+        if self.inner().counts.basic_acquire_if_alive() {
+            Some(unsafe { Arc::from_inner(self.inner.as_ptr()) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Clone for Weak<T, A> {
+    fn clone(&self) -> Self {
+        self.inner().weak_counts.basic_acquire(1);
+
+        Self {
+            inner: self.inner,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            Inner::weak_release(self.inner.as_ptr(), 1, 0);
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Send for Weak<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: Allocator + Send + Sync> Sync for Weak<T, A> {}
+
+impl<T: ?Sized, A: Allocator> core::fmt::Debug for Weak<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "(Weak)")
+    }
+}
+
+impl<T> Inner<[T]> {
+    /// Move the elements of `value` into a new `Inner<[T]>`, and return it
+    /// along with the layout of its allocation. Everything but the value is
+    /// left for `Inner::init`.
+    fn from_box(value: Box<[T]>) -> (*mut Inner<[T]>, Layout) {
+        let len = value.len();
+        let (layout, data) = Self::allocate(Self::layout_for(Layout::for_value(&*value)), &Global);
+        // Unlike `*mut T` in general, slice pointers can be built from their
+        // address and length, keeping the provenance of the allocation.
+        let inner = core::ptr::slice_from_raw_parts_mut(data as *mut T, len) as *mut Inner<[T]>;
+
+        unsafe {
+            let value = Box::into_raw(value);
+            core::ptr::copy_nonoverlapping(
+                value as *const T,
+                core::ptr::addr_of_mut!((*inner).value) as *mut T,
+                len,
+            );
+            // Free the box without dropping the elements.
+            drop(Box::from_raw(value as *mut [ManuallyDrop<T>]));
+        }
+        (inner, layout)
+    }
+}
+
+impl<T> From<Box<T>> for Arc<T> {
+    fn from(value: Box<T>) -> Self {
+        Arc::new(*value)
+    }
+}
+
+impl<T> From<Box<[T]>> for Arc<[T]> {
+    /// Move the elements of a `Box<[T]>` into a new `Arc<[T]>`.
+    fn from(value: Box<[T]>) -> Self {
+        let (inner, layout) = Inner::from_box(value);
+        unsafe {
+            Inner::init(inner, layout, Global);
+            Self::from_inner(inner)
+        }
+    }
+}
+
+impl From<Box<str>> for Arc<str> {
+    fn from(value: Box<str>) -> Self {
+        let (inner, layout) = Inner::from_box(value.into_boxed_bytes());
+        let inner = inner as *mut Inner<str>;
+        unsafe {
+            Inner::init(inner, layout, Global);
+            Self::from_inner(inner)
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Arc<[T]> {
+    fn from(value: Vec<T>) -> Self {
+        Arc::from(value.into_boxed_slice())
+    }
+}
+
+impl<T: Clone> From<&[T]> for Arc<[T]> {
+    fn from(value: &[T]) -> Self {
+        Arc::from(Box::<[T]>::from(value))
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(value: &str) -> Self {
+        Arc::from(Box::<str>::from(value))
+    }
+}
 
-impl<T: std::fmt::Debug> std::fmt::Debug for Arc<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", **self)
+impl From<String> for Arc<str> {
+    fn from(value: String) -> Self {
+        Arc::from(value.into_boxed_str())
     }
 }
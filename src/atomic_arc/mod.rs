@@ -5,82 +5,199 @@
 //! and we can also use the 4 lowest bits of the pointer which will be zero
 //! thanks to alignment requirements (glibc malloc already aligns pointers on
 //! 16 bytes, but for portability with different allocators we do it manually).
+//! Other targets fall back to double word atomics, see `packed`.
 //! We'll also pack the two inner counts on one word, for similar reasons.
+//! Weak references have their own pair of inner counts, so that they can be
+//! stored in an `AtomicWeak<T>` using the same scheme.
 //!
 //! [diff]: http://www.1024cores.net/home/lock-free-algorithms/object-life-time-management/differential-reference-counting/implementation
 //! [wikipedia]: https://en.wikipedia.org/wiki/X86-64#Virtual_address_space_details
 
+mod guard;
 mod inner;
+mod packed;
+#[cfg(feature = "serde")]
+mod serde;
 
-pub use self::inner::Arc;
+pub use self::guard::Guard;
 use self::inner::Inner;
-use std::sync::atomic::{AtomicUsize, Ordering};
+pub use self::inner::{Arc, Weak};
+use self::packed::{AtomicWord, Word};
+use crate::sync::Ordering;
+pub use allocator_api2::alloc::{Allocator, Global};
+
+/// Abort the process, as `std::process::abort` does. Without `std`, panic
+/// while panicking instead, which aborts whatever the panic strategy.
+#[cold]
+fn abort() -> ! {
+    #[cfg(feature = "std")]
+    std::process::abort();
+
+    #[cfg(not(feature = "std"))]
+    {
+        struct Abort;
+
+        impl Drop for Abort {
+            fn drop(&mut self) {
+                panic!("aborting");
+            }
+        }
+
+        let _abort = Abort;
+        panic!("aborting");
+    }
+}
 
-const PTR_MASK: usize = (-1isize as usize) >> 20;
-const PTR_SHIFT: usize = 4;
-const OUTER_COUNT_SHIFT: usize = 44;
-const MAX_OUTER_COUNT: usize = 1 << 20 - 1;
+// Tests run far fewer loads under Miri, make sure that they still reset
+// outer counts there.
+const OUTER_COUNT_RESET_THRESHOLD: usize = if cfg!(miri) { 1 << 8 } else { 1 << 16 };
 
 /// A type from which one can atomically store and load values of type
 /// [Arc<T>](self::Arc).
-pub struct AtomicArc<T, P = NonNull> {
-    // We store both the pointer and the outer count on one word:
-    // bits: 63-------44|43----------------------0
-    // data:    count   |   ptr without low bits
-    ptr_and_count: AtomicUsize,
-    _phantom: std::marker::PhantomData<T>,
-    _policy: std::marker::PhantomData<P>,
+pub struct AtomicArc<T: ?Sized, P: NullPolicy<T, A> = NonNull, A: Allocator = Global> {
+    // We store both the pointer and the outer count on one word, see
+    // `packed` for its layout.
+    ptr_and_count: AtomicWord,
+    _phantom: core::marker::PhantomData<T>,
+    _policy: core::marker::PhantomData<(P, A)>,
 }
 
 /// An [AtomicArc<T>](self::AtomicArc) with nullable contents.
-pub type NullableAtomicArc<T> = AtomicArc<T, Nullable>;
+pub type NullableAtomicArc<T, A = Global> = AtomicArc<T, Nullable, A>;
 
-impl<T> AtomicArc<T, NonNull> {
+/// A type from which one can atomically store and load values of type
+/// [Weak<T>](self::Weak).
+pub type AtomicWeak<T, A = Global> = AtomicArc<T, Downgraded, A>;
+
+impl<T: ?Sized, A: Allocator> AtomicArc<T, NonNull, A> {
     /// Return a new `AtomicArc<T>`.
-    pub fn new(arc: Arc<T>) -> Self {
+    pub fn new(arc: Arc<T, A>) -> Self {
         let ptr = NonNull::strong_acquire(&arc);
 
         Self {
             // Initially, the outer count is zero.
-            ptr_and_count: AtomicUsize::new(ptr >> PTR_SHIFT),
-            _phantom: std::marker::PhantomData,
-            _policy: std::marker::PhantomData,
+            ptr_and_count: AtomicWord::new(packed::pack(ptr)),
+            _phantom: core::marker::PhantomData,
+            _policy: core::marker::PhantomData,
         }
     }
+
+    /// Atomically load a [Guard<T>](self::Guard) from this `AtomicArc<T>`.
+    /// A guard is cheaper to drop than an `Arc<T>`, and can still be
+    /// upgraded to an `Arc<T>` if needed.
+    pub fn load_guard(&self) -> Guard<'_, T, A> {
+        unsafe { Guard::new(Inner::from_thin(self.acquire()), &self.ptr_and_count) }
+    }
+
+    /// Return a mutable reference to the value of this `AtomicArc<T>` if it
+    /// holds the only reference to it, like `Arc::get_mut`.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { self.get_mut_unique() }
+    }
 }
 
-impl<T> AtomicArc<T, Nullable> {
+impl<T: ?Sized, A: Allocator> AtomicArc<T, Nullable, A> {
     /// Return a new `AtomicArc<T>` with nullable contents.
-    pub fn new_nullable(arc: Option<Arc<T>>) -> Self {
+    pub fn new_nullable(arc: Option<Arc<T, A>>) -> Self {
         let ptr = Nullable::strong_acquire(&arc);
 
         Self {
             // Initially, the outer count is zero.
-            ptr_and_count: AtomicUsize::new(ptr >> PTR_SHIFT),
-            _phantom: std::marker::PhantomData,
-            _policy: std::marker::PhantomData,
+            ptr_and_count: AtomicWord::new(packed::pack(ptr)),
+            _phantom: core::marker::PhantomData,
+            _policy: core::marker::PhantomData,
         }
     }
+
+    /// Same as `AtomicArc::load_guard`, for nullable contents.
+    pub fn load_guard(&self) -> Option<Guard<'_, T, A>> {
+        match self.acquire() {
+            ptr if ptr.is_null() => None,
+            ptr => Some(unsafe { Guard::new(Inner::from_thin(ptr), &self.ptr_and_count) }),
+        }
+    }
+
+    /// Atomically take the contents of this `AtomicArc<T>`, leaving `None`.
+    pub fn take(&self) -> Option<Arc<T, A>> {
+        self.swap(None)
+    }
+
+    /// Return whether this `AtomicArc<T>` is currently empty.
+    pub fn is_none(&self) -> bool {
+        packed::ptr(self.ptr_and_count.load(Ordering::Acquire)).is_null()
+    }
+
+    /// Same as `AtomicArc::get_mut`, for nullable contents.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { self.get_mut_unique() }
+    }
 }
 
-impl<T, P: NullPolicy<T>> AtomicArc<T, P> {
-    /// Atomically load an `Arc<T>` from this `AtomicArc<T>`.
+impl<T: ?Sized, A: Allocator> AtomicArc<T, Downgraded, A> {
+    /// Return a new `AtomicWeak<T>`.
+    pub fn new_weak(weak: Weak<T, A>) -> Self {
+        let ptr = Downgraded::strong_acquire(&weak);
+
+        Self {
+            // Initially, the outer count is zero.
+            ptr_and_count: AtomicWord::new(packed::pack(ptr)),
+            _phantom: core::marker::PhantomData,
+            _policy: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator, P: NullPolicy<T, A>> AtomicArc<T, P, A> {
+    /// Atomically load an `Arc<T>` from this `AtomicArc<T>`. If the value is
+    /// only needed for the duration of a borrow of this `AtomicArc<T>`, see
+    /// `load_guard` for a cheaper alternative.
     ///
     /// # Notes
-    /// If `load` is called `1_048_575` times within a single `AtomicArc`, the
-    /// process will be aborted. In practice, this should never happen if care
-    /// is taken not to call `load` too many times, as the resulting `Guard<T>`
-    /// can be cloned instead.
+    /// Each `load` increments the outer count, which is folded back into the
+    /// inner basic count once it reaches `65_536`, so that an `AtomicArc<T>`
+    /// can be loaded an unbounded number of times. The process is only
+    /// aborted if `1_048_575` loads race with each other before the outer
+    /// count can be reset.
     pub fn load(&self) -> P::Arc {
-        let ptr_and_count = self
+        unsafe { P::from_ptr(self.acquire()) }
+    }
+
+    /// Consume this `AtomicArc<T>` and return its contents. This does not
+    /// need any atomic read-modify-write on the `AtomicArc<T>`, only one on
+    /// the counts of the pointee, to turn the reference it holds into the
+    /// one of the returned `Arc<T>`.
+    pub fn into_inner(self) -> P::Arc {
+        let mut this = core::mem::ManuallyDrop::new(self);
+        // Same as in `swap`.
+        let ptr_and_count = this
             .ptr_and_count
-            .fetch_add(1 << OUTER_COUNT_SHIFT, Ordering::Acquire);
+            .with_mut(|word| packed::increment(*word, P::MARKABLE));
+        unsafe {
+            Self::release(ptr_and_count);
+            P::from_ptr(packed::ptr(ptr_and_count))
+        }
+    }
 
-        if ptr_and_count >> OUTER_COUNT_SHIFT == MAX_OUTER_COUNT {
-            std::process::abort();
+    /// Increment the outer count and return the current pointer, for which
+    /// the caller now owns a reference.
+    fn acquire(&self) -> *mut u8 {
+        packed::ptr(self.acquire_word())
+    }
+
+    /// Same as `acquire`, returning the whole word the outer count was
+    /// incremented in.
+    fn acquire_word(&self) -> Word {
+        let ptr_and_count = self.ptr_and_count.fetch_increment(Ordering::Acquire);
+
+        if packed::count(ptr_and_count, P::MARKABLE) == packed::max_outer_count(P::MARKABLE) {
+            abort();
         }
 
-        unsafe { P::from_ptr((ptr_and_count & PTR_MASK) << PTR_SHIFT) }
+        if packed::count(ptr_and_count, P::MARKABLE) + 1 >= OUTER_COUNT_RESET_THRESHOLD {
+            unsafe { self.reset_outer_count(packed::increment(ptr_and_count, P::MARKABLE)) };
+        }
+
+        ptr_and_count
     }
 
     /// Atomically store an `Arc<T>` to this `AtomicArc<T>`.
@@ -89,7 +206,7 @@ impl<T, P: NullPolicy<T>> AtomicArc<T, P> {
 
         let old_ptr_and_count = self
             .ptr_and_count
-            .swap(new_ptr >> PTR_SHIFT, Ordering::AcqRel);
+            .swap(packed::pack(new_ptr), Ordering::AcqRel);
         unsafe { Self::release(old_ptr_and_count) }
     }
 
@@ -100,15 +217,14 @@ impl<T, P: NullPolicy<T>> AtomicArc<T, P> {
 
         let old_ptr_and_count = self
             .ptr_and_count
-            .swap(new_ptr >> PTR_SHIFT, Ordering::AcqRel)
-            // Increment the previous `outer` count before releasing, it has
-            // the same effect as `clone`-ing the returned `Arc`.
-            .checked_add(1 << OUTER_COUNT_SHIFT)
-            .unwrap();
+            .swap(packed::pack(new_ptr), Ordering::AcqRel);
+        // Increment the previous `outer` count before releasing, it has the
+        // same effect as `clone`-ing the returned `Arc`.
+        let old_ptr_and_count = packed::increment(old_ptr_and_count, P::MARKABLE);
 
         unsafe {
             Self::release(old_ptr_and_count);
-            P::from_ptr((old_ptr_and_count & PTR_MASK) << PTR_SHIFT)
+            P::from_ptr(packed::ptr(old_ptr_and_count))
         }
     }
 
@@ -120,14 +236,14 @@ impl<T, P: NullPolicy<T>> AtomicArc<T, P> {
 
         let mut old_ptr_and_count = self.ptr_and_count.load(Ordering::Relaxed);
         loop {
-            if (old_ptr_and_count & PTR_MASK) << PTR_SHIFT != current_ptr {
+            if packed::ptr(old_ptr_and_count) != current_ptr {
                 unsafe { P::strong_release(new_ptr) };
                 return false;
             }
 
             match self.ptr_and_count.compare_exchange_weak(
                 old_ptr_and_count,
-                new_ptr >> PTR_SHIFT,
+                packed::pack(new_ptr),
                 Ordering::AcqRel,
                 Ordering::Relaxed,
             ) {
@@ -139,98 +255,444 @@ impl<T, P: NullPolicy<T>> AtomicArc<T, P> {
             }
         }
     }
+
+    /// Compare the contents of this `AtomicArc<T>` with `current`, and store
+    /// `new` if they are equal. On success, return the previous contents. On
+    /// failure, return the actual contents along with `new`, so that the
+    /// caller can retry without an extra `load`.
+    pub fn compare_exchange(
+        &self,
+        current: &P::Arc,
+        new: P::Arc,
+    ) -> Result<P::Arc, (P::Arc, P::Arc)> {
+        let new_ptr = P::strong_acquire(&new);
+        let current_ptr = P::inner(current);
+
+        let mut old_ptr_and_count = self.ptr_and_count.load(Ordering::Relaxed);
+        loop {
+            if packed::ptr(old_ptr_and_count) != current_ptr {
+                // We need to own a reference before returning the actual
+                // contents, which may have changed back to `current` since.
+                let actual = self.load();
+                if P::inner(&actual) != current_ptr {
+                    unsafe { P::strong_release(new_ptr) };
+                    return Err((actual, new));
+                }
+                old_ptr_and_count = self.ptr_and_count.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.ptr_and_count.compare_exchange_weak(
+                old_ptr_and_count,
+                packed::pack(new_ptr),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(ptr_and_count) => {
+                    // Same as in `swap`.
+                    let ptr_and_count = packed::increment(ptr_and_count, P::MARKABLE);
+                    unsafe {
+                        Self::release(ptr_and_count);
+                        return Ok(P::from_ptr(packed::ptr(ptr_and_count)));
+                    }
+                }
+                Err(ptr_and_count) => old_ptr_and_count = ptr_and_count,
+            }
+        }
+    }
+
+    /// Repeatedly call `f` on the contents of this `AtomicArc<T>` and try to
+    /// store the result, until it succeeds or `f` returns `None`. Return the
+    /// previous contents in `Ok` on success, and the last contents seen by
+    /// `f` in `Err` otherwise.
+    pub fn fetch_update<F>(&self, mut f: F) -> Result<P::Arc, P::Arc>
+    where
+        F: FnMut(&P::Arc) -> Option<P::Arc>,
+    {
+        let mut current = self.load();
+        loop {
+            let new = match f(&current) {
+                Some(new) => new,
+                None => return Err(current),
+            };
+
+            match self.compare_exchange(&current, new) {
+                Ok(previous) => return Ok(previous),
+                Err((actual, _)) => current = actual,
+            }
+        }
+    }
+
+    /// Read-copy-update: replace the contents of this `AtomicArc<T>` with
+    /// the result of `f`, retrying if they changed concurrently, and return
+    /// the previous contents. `f` may be called several times.
+    pub fn rcu<F>(&self, mut f: F) -> P::Arc
+    where
+        F: FnMut(&P::Arc) -> P::Arc,
+    {
+        match self.fetch_update(|current| Some(f(current))) {
+            Ok(previous) => previous,
+            Err(..) => unreachable!(),
+        }
+    }
 }
 
-impl<T, P> AtomicArc<T, P> {
-    /// Always handle the null ptr case, so that we can use it in the `Drop`
-    /// impl (we cannot specialize it).
-    unsafe fn release(ptr_and_count: usize) {
-        let inner = ((ptr_and_count & PTR_MASK) << PTR_SHIFT) as *mut Inner<T>;
-        let count = ptr_and_count >> OUTER_COUNT_SHIFT;
-        if !inner.is_null() {
-            Inner::release(inner, -(count as i32), 1);
+/// A nullable `AtomicArc<T>` whose word has a mark, see `AtomicArc::mark`,
+/// at the cost of one bit of outer count.
+pub(crate) type MarkedAtomicArc<T, A = Global> = AtomicArc<T, Marked, A>;
+
+impl<T: ?Sized, A: Allocator> AtomicArc<T, Marked, A> {
+    /// Return a new `MarkedAtomicArc<T>`, which is not marked.
+    pub(crate) fn new_marked(arc: Option<Arc<T, A>>) -> Self {
+        let ptr = Marked::strong_acquire(&arc);
+
+        Self {
+            // Initially, the outer count is zero.
+            ptr_and_count: AtomicWord::new(packed::pack(ptr)),
+            _phantom: core::marker::PhantomData,
+            _policy: core::marker::PhantomData,
+        }
+    }
+
+    /// Set the mark of this `AtomicArc<T>`, which freezes its contents for
+    /// `swap_unmarked`. Return whether the mark was not already set. The
+    /// other methods storing to this `AtomicArc<T>` clear the mark.
+    pub(crate) fn mark(&self) -> bool {
+        let mut ptr_and_count = self.ptr_and_count.load(Ordering::Relaxed);
+        while !packed::is_marked(ptr_and_count) {
+            match self.ptr_and_count.compare_exchange_weak(
+                ptr_and_count,
+                packed::marked(ptr_and_count),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return true,
+                Err(actual) => ptr_and_count = actual,
+            }
+        }
+        false
+    }
+
+    /// Same as `load`, also returning whether the mark was set.
+    pub(crate) fn load_marked(&self) -> (Option<Arc<T, A>>, bool) {
+        let ptr_and_count = self.acquire_word();
+        let arc = unsafe { Marked::from_ptr(packed::ptr(ptr_and_count)) };
+        (arc, packed::is_marked(ptr_and_count))
+    }
+
+    /// Same as `swap`, unless the mark is set, in which case `arc` is given
+    /// back in `Err`.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn swap_unmarked(
+        &self,
+        arc: Option<Arc<T, A>>,
+    ) -> Result<Option<Arc<T, A>>, Option<Arc<T, A>>> {
+        let mut old_ptr_and_count = self.ptr_and_count.load(Ordering::Acquire);
+        if packed::is_marked(old_ptr_and_count) {
+            return Err(arc);
+        }
+
+        let new_ptr = Marked::strong_acquire(&arc);
+        loop {
+            match self.ptr_and_count.compare_exchange_weak(
+                old_ptr_and_count,
+                packed::pack(new_ptr),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(ptr_and_count) => {
+                    // Same as in `swap`.
+                    let ptr_and_count = packed::increment(ptr_and_count, true);
+                    unsafe {
+                        Self::release(ptr_and_count);
+                        return Ok(Marked::from_ptr(packed::ptr(ptr_and_count)));
+                    }
+                }
+                Err(ptr_and_count) if packed::is_marked(ptr_and_count) => {
+                    unsafe { <Marked as NullPolicy<T, A>>::strong_release(new_ptr) };
+                    return Err(arc);
+                }
+                Err(ptr_and_count) => old_ptr_and_count = ptr_and_count,
+            }
         }
     }
 }
 
-impl<T, P> Drop for AtomicArc<T, P> {
+impl<T: ?Sized, A: Allocator, P: NullPolicy<T, A>> AtomicArc<T, P, A> {
+    /// Move the outer count of `ptr_and_count` to the basic count of the
+    /// pointee, and reset the outer count to zero if the contents of this
+    /// `AtomicArc<T>` did not change in the meantime.
+    ///
+    /// Safety: the caller must own a reference on the pointee of
+    /// `ptr_and_count`, e.g. by having just loaded it.
+    unsafe fn reset_outer_count(&self, mut ptr_and_count: Word) {
+        let ptr = packed::ptr(ptr_and_count);
+        loop {
+            let count = packed::count(ptr_and_count, P::MARKABLE);
+            if packed::ptr(ptr_and_count) != ptr || count < OUTER_COUNT_RESET_THRESHOLD {
+                return;
+            }
+
+            // Increment the basic count first: the pointee stays alive because
+            // we own a reference on it, and the counts can only reach zero
+            // once we have either committed or undone the transfer.
+            P::basic_acquire(ptr, count as i32);
+
+            match self.ptr_and_count.compare_exchange(
+                ptr_and_count,
+                packed::without_count(ptr_and_count, P::MARKABLE),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return,
+                Err(actual) => {
+                    P::release(ptr, count as i32, 0);
+                    ptr_and_count = actual;
+                }
+            }
+        }
+    }
+
+    /// Return a mutable reference to the value of this `AtomicArc<T>` if it
+    /// holds the only reference to it.
+    ///
+    /// Safety: `P` must hold its reference in the counts of the value, i.e.
+    /// must not be `Downgraded`.
+    unsafe fn get_mut_unique(&mut self) -> Option<&mut T> {
+        // Nobody can load from us concurrently, but previous loads may still
+        // be accounted for in the outer count only: move it to the pointee.
+        let ptr_and_count = self.ptr_and_count.with_mut(|word| *word);
+        let (ptr, count) = (
+            packed::ptr(ptr_and_count),
+            packed::count(ptr_and_count, P::MARKABLE),
+        );
+        if ptr.is_null() {
+            return None;
+        }
+        if count > 0 {
+            P::basic_acquire(ptr, count as i32);
+            self.ptr_and_count
+                .with_mut(|word| *word = packed::without_count(*word, P::MARKABLE));
+        }
+
+        let inner = Inner::<T, A>::from_thin(ptr);
+        if (*inner).is_unique(0, 1) {
+            Some(&mut (*inner).value)
+        } else {
+            None
+        }
+    }
+
+    unsafe fn release(ptr_and_count: Word) {
+        let count = packed::count(ptr_and_count, P::MARKABLE);
+        P::release(packed::ptr(ptr_and_count), -(count as i32), 1);
+    }
+}
+
+impl<T: ?Sized, A: Allocator, P: NullPolicy<T, A>> Drop for AtomicArc<T, P, A> {
     fn drop(&mut self) {
         unsafe { Self::release(self.ptr_and_count.load(Ordering::Acquire)) }
     }
 }
 
-unsafe impl<T: Send> Send for AtomicArc<T> {}
-unsafe impl<T: Sync> Sync for AtomicArc<T> {}
+// Any thread loading from a shared `AtomicArc` gets an `Arc`, so the bounds
+// are the ones of `Arc` whatever the policy.
+unsafe impl<T: ?Sized + Send + Sync, P: NullPolicy<T, A>, A: Allocator + Send + Sync> Send
+    for AtomicArc<T, P, A>
+{
+}
+unsafe impl<T: ?Sized + Send + Sync, P: NullPolicy<T, A>, A: Allocator + Send + Sync> Sync
+    for AtomicArc<T, P, A>
+{
+}
+
+impl<T: ?Sized, A: Allocator, P: NullPolicy<T, A>> core::fmt::Debug for AtomicArc<T, P, A>
+where
+    P::Arc: core::fmt::Debug,
+{
+    /// Load the current value and print it.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.load().fmt(f)
+    }
+}
+
+impl<T: Default> Default for AtomicArc<T> {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Default for NullableAtomicArc<T, A> {
+    fn default() -> Self {
+        Self::new_nullable(None)
+    }
+}
 
 pub struct NonNull;
 pub struct Nullable;
+pub struct Downgraded;
+pub(crate) struct Marked;
 
 mod private {
-    pub trait Sealed {}
+    pub trait Sealed {
+        /// Whether the highest bit of the word is a mark rather than part of
+        /// the outer count, see `packed`.
+        const MARKABLE: bool = false;
+    }
+
     impl Sealed for super::NonNull {}
     impl Sealed for super::Nullable {}
+    impl Sealed for super::Downgraded {}
+    impl Sealed for super::Marked {
+        const MARKABLE: bool = true;
+    }
 }
 
-/// Helper trait to handle both `Option<Arc<T>>` and `Arc<T>`. This trait is
-/// sealed to prevent downstream users from implementing it.
-pub trait NullPolicy<T>: private::Sealed {
+/// Helper trait to handle `Arc<T>`, `Option<Arc<T>>` and `Weak<T>`. This
+/// trait is sealed to prevent downstream users from implementing it.
+pub trait NullPolicy<T: ?Sized, A: Allocator = Global>: private::Sealed {
     type Arc;
 
-    fn strong_acquire(arc: &Self::Arc) -> usize;
-    unsafe fn strong_release(ptr: usize);
-    fn inner(arc: &Self::Arc) -> usize;
-    unsafe fn from_ptr(ptr: usize) -> Self::Arc;
+    fn strong_acquire(arc: &Self::Arc) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must have been returned by `strong_acquire`, and must not be
+    /// released more than once.
+    unsafe fn strong_release(ptr: *mut u8) {
+        Self::release(ptr, 0, 1)
+    }
+
+    /// Add `basic` to the basic count of the counts referenced by `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` must point to a valid `Inner<T>`, or be null.
+    unsafe fn basic_acquire(ptr: *mut u8, basic: i32);
+
+    /// Substract `basic` and `strong` to the counts referenced by `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` must point to a valid `Inner<T>`, or be null, and the caller
+    /// must own the references it releases.
+    unsafe fn release(ptr: *mut u8, basic: i32, strong: usize);
+
+    fn inner(arc: &Self::Arc) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must carry a basic count which is transferred to the returned
+    /// value.
+    unsafe fn from_ptr(ptr: *mut u8) -> Self::Arc;
 }
 
-impl<T> NullPolicy<T> for NonNull {
-    type Arc = Arc<T>;
+impl<T: ?Sized, A: Allocator> NullPolicy<T, A> for NonNull {
+    type Arc = Arc<T, A>;
 
-    fn strong_acquire(arc: &Arc<T>) -> usize {
-        let inner = arc.inner();
-        inner.strong_acquire();
-        inner as *const _ as usize
+    fn strong_acquire(arc: &Arc<T, A>) -> *mut u8 {
+        arc.inner().counts.strong_acquire();
+        Arc::thin_ptr(arc)
     }
 
-    unsafe fn strong_release(ptr: usize) {
-        Inner::release(ptr as *mut Inner<T>, 0, 1);
+    unsafe fn basic_acquire(ptr: *mut u8, basic: i32) {
+        (*Inner::<T, A>::from_thin(ptr)).counts.basic_acquire(basic);
     }
 
-    fn inner(arc: &Arc<T>) -> usize {
-        arc.inner() as *const _ as usize
+    unsafe fn release(ptr: *mut u8, basic: i32, strong: usize) {
+        Inner::release(Inner::<T, A>::from_thin(ptr), basic, strong);
     }
 
-    unsafe fn from_ptr(ptr: usize) -> Arc<T> {
-        Arc::from_inner(ptr as *mut Inner<T>)
+    fn inner(arc: &Arc<T, A>) -> *mut u8 {
+        Arc::thin_ptr(arc)
+    }
+
+    unsafe fn from_ptr(ptr: *mut u8) -> Arc<T, A> {
+        Arc::from_inner(Inner::from_thin(ptr))
     }
 }
 
-impl<T> NullPolicy<T> for Nullable {
-    type Arc = Option<Arc<T>>;
+impl<T: ?Sized, A: Allocator> NullPolicy<T, A> for Nullable {
+    type Arc = Option<Arc<T, A>>;
 
-    fn strong_acquire(arc: &Option<Arc<T>>) -> usize {
+    fn strong_acquire(arc: &Option<Arc<T, A>>) -> *mut u8 {
         arc.as_ref()
             .map(|arc| NonNull::strong_acquire(arc))
-            .unwrap_or(0)
+            .unwrap_or(core::ptr::null_mut())
+    }
+
+    unsafe fn basic_acquire(ptr: *mut u8, basic: i32) {
+        if !ptr.is_null() {
+            <NonNull as NullPolicy<T, A>>::basic_acquire(ptr, basic);
+        }
     }
 
-    unsafe fn strong_release(ptr: usize) {
-        if ptr != 0 {
-            Inner::release(ptr as *mut Inner<T>, 0, 1);
+    unsafe fn release(ptr: *mut u8, basic: i32, strong: usize) {
+        if !ptr.is_null() {
+            <NonNull as NullPolicy<T, A>>::release(ptr, basic, strong);
         }
     }
 
-    fn inner(arc: &Option<Arc<T>>) -> usize {
+    fn inner(arc: &Option<Arc<T, A>>) -> *mut u8 {
         match arc.as_ref() {
-            Some(arc) => arc.inner() as *const _ as usize,
-            None => 0,
+            Some(arc) => Arc::thin_ptr(arc),
+            None => core::ptr::null_mut(),
         }
     }
 
-    unsafe fn from_ptr(ptr: usize) -> Option<Arc<T>> {
-        if ptr != 0 {
-            Some(Arc::from_inner(ptr as *mut Inner<T>))
+    unsafe fn from_ptr(ptr: *mut u8) -> Option<Arc<T, A>> {
+        if !ptr.is_null() {
+            Some(Arc::from_inner(Inner::from_thin(ptr)))
         } else {
             None
         }
     }
 }
+
+impl<T: ?Sized, A: Allocator> NullPolicy<T, A> for Downgraded {
+    type Arc = Weak<T, A>;
+
+    fn strong_acquire(weak: &Weak<T, A>) -> *mut u8 {
+        weak.inner().weak_counts.strong_acquire();
+        Weak::thin_ptr(weak)
+    }
+
+    unsafe fn basic_acquire(ptr: *mut u8, basic: i32) {
+        (*Inner::<T, A>::from_thin(ptr))
+            .weak_counts
+            .basic_acquire(basic);
+    }
+
+    unsafe fn release(ptr: *mut u8, basic: i32, strong: usize) {
+        Inner::weak_release(Inner::<T, A>::from_thin(ptr), basic, strong);
+    }
+
+    fn inner(weak: &Weak<T, A>) -> *mut u8 {
+        Weak::thin_ptr(weak)
+    }
+
+    unsafe fn from_ptr(ptr: *mut u8) -> Weak<T, A> {
+        Weak::from_inner(Inner::from_thin(ptr))
+    }
+}
+
+/// Same as `Nullable`, for a `MarkedAtomicArc<T>`.
+impl<T: ?Sized, A: Allocator> NullPolicy<T, A> for Marked {
+    type Arc = Option<Arc<T, A>>;
+
+    fn strong_acquire(arc: &Option<Arc<T, A>>) -> *mut u8 {
+        <Nullable as NullPolicy<T, A>>::strong_acquire(arc)
+    }
+
+    unsafe fn basic_acquire(ptr: *mut u8, basic: i32) {
+        <Nullable as NullPolicy<T, A>>::basic_acquire(ptr, basic);
+    }
+
+    unsafe fn release(ptr: *mut u8, basic: i32, strong: usize) {
+        <Nullable as NullPolicy<T, A>>::release(ptr, basic, strong);
+    }
+
+    fn inner(arc: &Option<Arc<T, A>>) -> *mut u8 {
+        <Nullable as NullPolicy<T, A>>::inner(arc)
+    }
+
+    unsafe fn from_ptr(ptr: *mut u8) -> Option<Arc<T, A>> {
+        <Nullable as NullPolicy<T, A>>::from_ptr(ptr)
+    }
+}
@@ -0,0 +1,277 @@
+//! Layouts of the word storing both the pointer and the outer count of an
+//! `AtomicArc<T>`. The rest of the module only goes through `AtomicWord`,
+//! `pack`, `ptr`, `count`, `increment`, `decrement` and the mark helpers, so
+//! that the layout can be selected by `cfg`:
+//!
+//! - `compact` is used on x86_64: user-space addresses fit on 48 bits and
+//!   `Inner<T>` is 16-byte aligned, so the pointer only needs 44 bits, and
+//!   the outer count gets the 20 remaining ones of a single word.
+//! - `wide` is used everywhere else, e.g. with 52-bit addresses or tagged
+//!   pointers on aarch64, and on 32-bit targets: the full pointer and the
+//!   outer count each get their own half of a double word, which is updated
+//!   with a double-word CAS where the target has one, or with a lock-based
+//!   fallback otherwise, see `portable_atomic`.
+//!
+//! The words of a `MarkedAtomicArc<T>`, which only the hash map uses, give
+//! the highest bit of the outer count to a mark instead, which freezes their
+//! contents, see `AtomicArc::mark`. It is kept by outer count updates, and
+//! cleared by stores. Functions whose result depends on it take `markable`,
+//! which is `NullPolicy::MARKABLE` for the `AtomicArc<T>` at hand.
+//!
+//! The `wide-pointers` feature forces the `wide` layout on x86_64, which is
+//! also needed with 5-level paging if memory can be mapped above 47 bits.
+//!
+//! Pointers keep their provenance through the `compact` layout, whose word
+//! is itself a pointer only tagged with the outer count, so that it passes
+//! Miri with `-Zmiri-strict-provenance`. There is no double-word atomic
+//! pointer to do the same in the `wide` layout, which falls back to exposing
+//! the provenance of the pointers it stores.
+
+use crate::sync::Ordering;
+
+#[cfg(all(target_arch = "x86_64", not(feature = "wide-pointers")))]
+pub(super) use self::compact::*;
+#[cfg(not(all(target_arch = "x86_64", not(feature = "wide-pointers"))))]
+pub(super) use self::wide::*;
+
+#[cfg(all(target_arch = "x86_64", not(feature = "wide-pointers")))]
+mod compact {
+    use super::AtomicWord;
+    use crate::sync::{AtomicPtr, Ordering};
+
+    // bits: 63-------44|43----------------------0
+    // data:    count   |   ptr without low bits
+    //
+    // In markable words, bit 63 is the mark instead of a bit of the count.
+    pub type Word = *mut u8;
+    pub(super) type RawAtomicWord = AtomicPtr<u8>;
+
+    const PTR_MASK: usize = (-1isize as usize) >> 20;
+    const PTR_SHIFT: usize = 4;
+    const OUTER_COUNT_SHIFT: usize = 44;
+    const MARK: usize = 1 << 63;
+
+    /// The outer count that a single load adds.
+    const ONE: usize = 1 << OUTER_COUNT_SHIFT;
+
+    pub const fn max_outer_count(markable: bool) -> usize {
+        if markable {
+            (1 << 19) - 1
+        } else {
+            (1 << 20) - 1
+        }
+    }
+
+    /// Return the bits of the mark, if any.
+    const fn mark(markable: bool) -> usize {
+        if markable {
+            MARK
+        } else {
+            0
+        }
+    }
+
+    /// Return whether `ptr` can be stored in a `Word`.
+    pub fn is_packable(ptr: *mut u8) -> bool {
+        ptr.addr() >> 48 == 0 && ptr.addr() & ((1 << PTR_SHIFT) - 1) == 0
+    }
+
+    /// Return a `Word` holding `ptr` and an outer count of zero.
+    pub fn pack(ptr: *mut u8) -> Word {
+        ptr.map_addr(|addr| addr >> PTR_SHIFT)
+    }
+
+    pub fn ptr(word: Word) -> *mut u8 {
+        word.map_addr(|addr| (addr & PTR_MASK) << PTR_SHIFT)
+    }
+
+    pub fn count(word: Word, markable: bool) -> usize {
+        (word.addr() & !mark(markable)) >> OUTER_COUNT_SHIFT
+    }
+
+    pub fn is_marked(word: Word) -> bool {
+        word.addr() & MARK != 0
+    }
+
+    /// Return `word` with its mark set.
+    pub fn marked(word: Word) -> Word {
+        word.map_addr(|addr| addr | MARK)
+    }
+
+    /// Return `word` with an outer count of zero, keeping its mark.
+    pub fn without_count(word: Word, markable: bool) -> Word {
+        word.map_addr(|addr| addr & (PTR_MASK | mark(markable)))
+    }
+
+    /// Return `word` with its outer count incremented, panicking on
+    /// overflow.
+    pub fn increment(word: Word, markable: bool) -> Word {
+        assert!(
+            count(word, markable) < max_outer_count(markable),
+            "outer count overflow"
+        );
+        word.map_addr(|addr| addr + ONE)
+    }
+
+    /// Return `word` with its outer count decremented, which must be
+    /// positive.
+    pub fn decrement(word: Word) -> Word {
+        word.map_addr(|addr| addr - ONE)
+    }
+
+    impl AtomicWord {
+        /// Increment the outer count, and return the previous word.
+        #[cfg(not(loom))]
+        pub fn fetch_increment(&self, order: Ordering) -> Word {
+            self.0.fetch_byte_add(ONE, order)
+        }
+
+        /// `loom` has no `fetch_byte_add`, but the models only need the
+        /// increment to be atomic.
+        #[cfg(loom)]
+        pub fn fetch_increment(&self, order: Ordering) -> Word {
+            self.0
+                .fetch_update(order, Ordering::Relaxed, |word| {
+                    Some(word.map_addr(|addr| addr + ONE))
+                })
+                .unwrap()
+        }
+    }
+}
+
+#[cfg(all(loom, target_pointer_width = "64", not(target_arch = "x86_64")))]
+compile_error!("loom has no double word atomics, models can only run on x86_64");
+#[cfg(all(loom, feature = "wide-pointers"))]
+compile_error!("loom has no double word atomics, models cannot use `wide-pointers`");
+
+#[cfg(not(all(target_arch = "x86_64", not(feature = "wide-pointers"))))]
+mod wide {
+    use super::AtomicWord;
+    use crate::sync::Ordering;
+
+    // bits: 2n-1|2n-2----n|n-1-----0
+    // data: mark|  count  |   ptr
+    //
+    // The count never reaches the mark, which stays clear unless markable.
+    #[cfg(target_pointer_width = "64")]
+    pub type Word = u128;
+    #[cfg(target_pointer_width = "64")]
+    pub(super) type RawAtomicWord = portable_atomic::AtomicU128;
+    #[cfg(target_pointer_width = "32")]
+    pub type Word = u64;
+    #[cfg(target_pointer_width = "32")]
+    pub(super) type RawAtomicWord = crate::sync::AtomicU64;
+
+    const OUTER_COUNT_SHIFT: u32 = usize::BITS;
+    const MARK: Word = 1 << (Word::BITS - 1);
+
+    /// The outer count that a single load adds.
+    const ONE: Word = 1 << OUTER_COUNT_SHIFT;
+    // Outer counts are moved to the basic count, which is an `i32`.
+    pub const fn max_outer_count(_markable: bool) -> usize {
+        i32::MAX as usize
+    }
+
+    /// Return whether `ptr` can be stored in a `Word`.
+    pub fn is_packable(_ptr: *mut u8) -> bool {
+        true
+    }
+
+    /// Return a `Word` holding `ptr` and an outer count of zero.
+    pub fn pack(ptr: *mut u8) -> Word {
+        ptr.expose_provenance() as Word
+    }
+
+    pub fn ptr(word: Word) -> *mut u8 {
+        core::ptr::with_exposed_provenance_mut(word as usize)
+    }
+
+    pub fn count(word: Word, _markable: bool) -> usize {
+        ((word & !MARK) >> OUTER_COUNT_SHIFT) as usize
+    }
+
+    pub fn is_marked(word: Word) -> bool {
+        word & MARK != 0
+    }
+
+    /// Return `word` with its mark set.
+    pub fn marked(word: Word) -> Word {
+        word | MARK
+    }
+
+    /// Return `word` with an outer count of zero, keeping its mark.
+    pub fn without_count(word: Word, _markable: bool) -> Word {
+        word & (MARK | usize::MAX as Word)
+    }
+
+    /// Return `word` with its outer count incremented, panicking on
+    /// overflow.
+    pub fn increment(word: Word, markable: bool) -> Word {
+        assert!(
+            count(word, markable) < max_outer_count(markable),
+            "outer count overflow"
+        );
+        word + ONE
+    }
+
+    /// Return `word` with its outer count decremented, which must be
+    /// positive.
+    pub fn decrement(word: Word) -> Word {
+        word - ONE
+    }
+
+    impl AtomicWord {
+        /// Increment the outer count, and return the previous word.
+        pub fn fetch_increment(&self, order: Ordering) -> Word {
+            self.0.fetch_add(ONE, order)
+        }
+    }
+}
+
+/// The operations of the underlying atomic that both layouts share.
+pub struct AtomicWord(RawAtomicWord);
+
+impl AtomicWord {
+    pub fn new(word: Word) -> Self {
+        AtomicWord(RawAtomicWord::new(word))
+    }
+
+    pub fn load(&self, order: Ordering) -> Word {
+        self.0.load(order)
+    }
+
+    pub fn swap(&self, word: Word, order: Ordering) -> Word {
+        self.0.swap(word, order)
+    }
+
+    pub fn compare_exchange(
+        &self,
+        current: Word,
+        new: Word,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Word, Word> {
+        self.0.compare_exchange(current, new, success, failure)
+    }
+
+    pub fn compare_exchange_weak(
+        &self,
+        current: Word,
+        new: Word,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Word, Word> {
+        self.0.compare_exchange_weak(current, new, success, failure)
+    }
+
+    /// Run `f` on the word, to which we have exclusive access.
+    #[cfg(not(loom))]
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut Word) -> R) -> R {
+        f(self.0.get_mut())
+    }
+
+    #[cfg(loom)]
+    pub fn with_mut<R>(&mut self, f: impl FnOnce(&mut Word) -> R) -> R {
+        self.0.with_mut(f)
+    }
+}
@@ -0,0 +1,58 @@
+//! `Serialize` and `Deserialize` for `Arc<T>` and the atomic pointers, which
+//! are serialized as the value they currently point to.
+
+use super::{Allocator, Arc, AtomicArc, NonNull, NullableAtomicArc};
+use alloc::boxed::Box;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+impl<T: ?Sized + Serialize, A: Allocator> Serialize for Arc<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+/// Going through `Box<T>` supports unsized values such as `Arc<str>`.
+impl<'de, T: ?Sized> Deserialize<'de> for Arc<T>
+where
+    Box<T>: Deserialize<'de>,
+    Arc<T>: From<Box<T>>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Box::deserialize(deserializer).map(Arc::from)
+    }
+}
+
+impl<T: ?Sized + Serialize, A: Allocator> Serialize for AtomicArc<T, NonNull, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (*self.load_guard()).serialize(serializer)
+    }
+}
+
+impl<'de, T: ?Sized> Deserialize<'de> for AtomicArc<T>
+where
+    Box<T>: Deserialize<'de>,
+    Arc<T>: From<Box<T>>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Arc::deserialize(deserializer).map(AtomicArc::new)
+    }
+}
+
+impl<T: ?Sized + Serialize, A: Allocator> Serialize for NullableAtomicArc<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.load_guard() {
+            Some(guard) => serializer.serialize_some(&*guard),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de, T: ?Sized> Deserialize<'de> for NullableAtomicArc<T>
+where
+    Box<T>: Deserialize<'de>,
+    Arc<T>: From<Box<T>>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<Arc<T>>::deserialize(deserializer).map(AtomicArc::new_nullable)
+    }
+}
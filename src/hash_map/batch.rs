@@ -0,0 +1,90 @@
+//! Batched operations. Hashes are computed upfront so that we can visit
+//! buckets in memory order, load the table only once per batch, and prefetch
+//! the buckets we are about to probe.
+
+use super::{HashMap, PREFETCH_DISTANCE};
+use crate::atomic_arc::{Allocator, Arc};
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash};
+
+/// How many items `insert_many` buffers, hashes and sorts at a time.
+const INSERT_CHUNK: usize = 1024;
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher, A: Allocator + Clone> HashMap<K, V, S, A> {
+    /// Look up all `keys` at once. The result at index `i` is the value
+    /// associated with `keys[i]`.
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<Arc<V, A>>> {
+        let table = self.table.load_guard();
+        let hashes = keys.iter().map(|key| self.hash(key)).collect::<Vec<_>>();
+        let order = table.probe_order(&hashes);
+
+        let mut values = (0..keys.len()).map(|_| None).collect::<Vec<_>>();
+        for (i, &j) in order.iter().enumerate() {
+            if let Some(&ahead) = order.get(i + PREFETCH_DISTANCE) {
+                table.hash_into(hashes[ahead]).prefetch();
+            }
+
+            let key = &keys[j];
+            values[j] = table.get(hashes[j], &mut |k: &K| k == key);
+        }
+        values
+    }
+
+    /// Insert all `items`, `INSERT_CHUNK` of them at a time: the inserts of
+    /// a chunk share a table load and visit buckets in memory order, and the
+    /// last of duplicate keys wins. Room for the lower bound of the size hint
+    /// of `items` is reserved upfront, which saves intermediate resizes
+    /// unless other threads insert concurrently.
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&self, items: I) {
+        let mut items = items.into_iter();
+        self.reserve(items.size_hint().0);
+
+        let mut chunk = Vec::new();
+        loop {
+            chunk.extend(items.by_ref().take(INSERT_CHUNK).map(Some));
+            if chunk.is_empty() {
+                return;
+            }
+            self.insert_chunk(&mut chunk);
+            chunk.clear();
+        }
+    }
+
+    fn insert_chunk(&self, items: &mut [Option<(K, V)>]) {
+        let mut table = self.table.load();
+        let hashes = items
+            .iter()
+            .map(|item| self.hash(&item.as_ref().unwrap().0))
+            .collect::<Vec<_>>();
+        let order = table.probe_order(&hashes);
+
+        for (i, &j) in order.iter().enumerate() {
+            if let Some(&ahead) = order.get(i + PREFETCH_DISTANCE) {
+                table.hash_into(hashes[ahead]).prefetch();
+            }
+
+            let (key, value) = items[j].take().unwrap();
+            if self.insert_with_table(&table, hashes[j], key, value) {
+                table = self.table.load();
+            }
+        }
+    }
+
+    /// Remove all `keys` at once.
+    pub fn remove_many(&self, keys: &[K]) {
+        let mut table = self.table.load();
+        let hashes = keys.iter().map(|key| self.hash(key)).collect::<Vec<_>>();
+        let order = table.probe_order(&hashes);
+
+        for (i, &j) in order.iter().enumerate() {
+            if let Some(&ahead) = order.get(i + PREFETCH_DISTANCE) {
+                table.hash_into(hashes[ahead]).prefetch();
+            }
+
+            let key = &keys[j];
+            if self.remove_with_table(&table, hashes[j], |k| k == key) {
+                table = self.table.load();
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Iteration over the entries of a map. Keys are cloned, since the entries
+//! can be moved to another table while we iterate, and values are returned
+//! as `Arc`s as with `HashMap::get`.
+
+use super::{Buckets, HashMap};
+use crate::atomic_arc::{Allocator, Arc, Global};
+use alloc::vec::Vec;
+
+impl<K: Clone, V, S, A: Allocator> HashMap<K, V, S, A> {
+    /// Return an iterator over the entries of the map, in no particular
+    /// order. Iteration is weakly consistent: entries which are in the map
+    /// for the whole iteration are returned exactly once, and entries which
+    /// are inserted or removed concurrently may or may not be.
+    pub fn iter(&self) -> Iter<K, V, A> {
+        Iter {
+            table: self.table.load(),
+            bucket: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// There is no owned counterpart: iterators keep their table alive after
+/// the map is gone, so entries cannot be moved out of a table, and an owned
+/// iterator would have to clone keys like this one.
+impl<K: Clone, V, S, A: Allocator> IntoIterator for &HashMap<K, V, S, A> {
+    type Item = (K, Arc<V, A>);
+    type IntoIter = Iter<K, V, A>;
+
+    fn into_iter(self) -> Iter<K, V, A> {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of a `HashMap`, see `HashMap::iter`. It
+/// keeps the table it started with alive, but not the map.
+pub struct Iter<K, V, A: Allocator = Global> {
+    table: Arc<Buckets<K, V, A>, A>,
+    bucket: usize,
+    // The entries left from the last bucket visited.
+    entries: Vec<(K, Arc<V, A>)>,
+}
+
+impl<K: Clone, V, A: Allocator> Iterator for Iter<K, V, A> {
+    type Item = (K, Arc<V, A>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.pop() {
+                return Some(entry);
+            }
+            if self.bucket == self.table.buckets.len() {
+                return None;
+            }
+            self.table.collect_bucket(self.bucket, &mut self.entries);
+            self.bucket += 1;
+        }
+    }
+}
@@ -1,217 +1,498 @@
+mod batch;
+mod iter;
+#[cfg(feature = "rayon")]
+mod rayon;
+#[cfg(feature = "serde")]
+mod serde;
+#[cfg(feature = "std")]
+mod snapshot;
 mod virtual_bucket;
 
-use self::virtual_bucket::{ResizeNeeded, VirtualBucket};
-use crate::atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use self::virtual_bucket::{Lookup, Moved, Retry, VirtualBucket};
+use crate::atomic_arc::{Allocator, Arc, AtomicArc, Global, NullableAtomicArc};
+use crate::sync::{AtomicU32, AtomicU64, Ordering};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+use core::hash::{BuildHasher, Hash};
 
-pub use fxhash::FxBuildHasher as DefaultBuildHasher;
+pub use self::iter::Iter;
+#[cfg(feature = "rayon")]
+pub use self::rayon::ParIter;
+#[cfg(feature = "std")]
+pub use self::snapshot::{Checksum, Codec};
+/// The hasher of maps which do not pick one.
+///
+/// Breaking change in 0.2: this is the Fx hasher of `rustc-hash`, which
+/// also builds without `std`, instead of the one of `fxhash`. Both hash
+/// keys differently, so hashes computed by 0.1 and kept around for the
+/// `raw_*` methods must be computed again with `HashMap::hash`.
+pub use rustc_hash::FxBuildHasher as DefaultBuildHasher;
 
-pub struct HashMap<K, V, S = DefaultBuildHasher> {
-    table: AtomicArc<Buckets<K, V>>,
+/// A concurrent hash map. The table, the entries and the values are
+/// allocated with `A`, which defaults to the global allocator, see
+/// `HashMap::new_in`.
+///
+/// Operations never wait for each other: one which finds its bucket being
+/// copied by a resize helps finishing the resize, and retries on the new
+/// table. Dropping values and comparing keys may thus use the map itself.
+pub struct HashMap<K, V, S = DefaultBuildHasher, A: Allocator = Global> {
+    table: AtomicArc<Buckets<K, V, A>, crate::atomic_arc::NonNull, A>,
     items: AtomicU64,
     hash_builder: S,
+    alloc: A,
 }
 
 const MIN_LOAD_FACTOR_FOR_RESIZE: f32 = 0.5;
 const DEPTH_TRESHOLD: i32 = 1;
-const N: usize = 7;
+const N: usize = 13;
+/// How many keys ahead batched operations prefetch buckets.
+const PREFETCH_DISTANCE: usize = 4;
 
 impl<K, V> HashMap<K, V> {
     pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<K, V, A: Allocator + Clone> HashMap<K, V, DefaultBuildHasher, A> {
+    /// Return a new map allocating with `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_hasher_in(Default::default(), alloc)
+    }
+}
+
+impl<K, V, S, A: Allocator + Clone> HashMap<K, V, S, A> {
+    /// Return a new map hashing keys with `hash_builder` and allocating with
+    /// `alloc`.
+    pub fn with_hasher_in(hash_builder: S, alloc: A) -> Self {
         Self {
-            table: AtomicArc::new(Arc::new(Buckets::new(1))),
+            table: AtomicArc::new(Arc::new_in(Buckets::new(1, alloc.clone()), alloc.clone())),
             items: AtomicU64::new(0),
-            hash_builder: Default::default(),
+            hash_builder,
+            alloc,
         }
     }
+
+    /// The number of entries, including removed ones until a resize drops
+    /// them.
+    #[cfg(test)]
+    pub(crate) fn items(&self) -> u64 {
+        self.items.load(Ordering::Relaxed)
+    }
 }
 
-impl<K: Eq + Hash + Clone, V, S: BuildHasher> HashMap<K, V, S> {
-    fn hash(&self, key: &K) -> u64 {
-        let mut state = self.hash_builder.build_hasher();
-        key.hash(&mut state);
-        match state.finish() {
-            0 => 1,
-            hash => hash,
-        }
+impl<K, V, S: Default, A: Allocator + Clone + Default> Default for HashMap<K, V, S, A> {
+    fn default() -> Self {
+        Self::with_hasher_in(S::default(), A::default())
+    }
+}
+
+impl<K: Clone + core::fmt::Debug, V: core::fmt::Debug, S, A: Allocator> core::fmt::Debug
+    for HashMap<K, V, S, A>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// The clone is a snapshot of the map, see `HashMap::iter`, with values
+/// cloned as well so that the maps are independent.
+impl<K, V, S, A> Clone for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+    A: Allocator + Clone,
+{
+    fn clone(&self) -> Self {
+        let map = Self::with_hasher_in(self.hash_builder.clone(), self.alloc.clone());
+        map.insert_many(self.iter().map(|(key, value)| (key, V::clone(&value))));
+        map
+    }
+}
+
+/// Maps are equal if they have the same keys with equal values. Either map
+/// being modified concurrently makes the result meaningless.
+impl<K, V, S, A> PartialEq for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone,
+    V: PartialEq,
+    S: BuildHasher,
+    A: Allocator + Clone,
+{
+    fn eq(&self, other: &Self) -> bool {
+        let entries = self.iter().collect::<alloc::vec::Vec<_>>();
+        other.iter().count() == entries.len()
+            && entries
+                .iter()
+                .all(|(key, value)| other.get(key).is_some_and(|other| **value == *other))
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Eq, S: BuildHasher, A: Allocator + Clone> Eq for HashMap<K, V, S, A> {}
+
+/// Items are inserted with `HashMap::insert_many`, so that the last of
+/// duplicate keys wins.
+impl<K, V, S, A> core::iter::FromIterator<(K, V)> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Default,
+    A: Allocator + Clone + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = Self::default();
+        map.insert_many(iter);
+        map
+    }
+}
+
+impl<K, V, S, A> Extend<(K, V)> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+    A: Allocator + Clone,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.insert_many(iter);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V, S, A, T> From<std::collections::HashMap<K, V, T>> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher + Default,
+    A: Allocator + Clone + Default,
+{
+    fn from(map: std::collections::HashMap<K, V, T>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+// Keys are read concurrently by all the threads using the map, and values
+// are handed out as `Arc`s: either may be dropped by any thread.
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send, A: Allocator + Send + Sync> Send
+    for HashMap<K, V, S, A>
+{
+}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync, A: Allocator + Send + Sync> Sync
+    for HashMap<K, V, S, A>
+{
+}
+
+/// Zero is remapped so that the values returned by `HashMap::hash` stay
+/// stable for callers of the `raw_*` methods.
+fn remap_hash(hash: u64) -> u64 {
+    match hash {
+        0 => 1,
+        hash => hash,
+    }
+}
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher, A: Allocator + Clone> HashMap<K, V, S, A> {
+    /// Return the hash of `key` as used by this map. The result can be fed
+    /// to the `raw_*` methods of any map sharing the same hasher.
+    pub fn hash(&self, key: &K) -> u64 {
+        remap_hash(self.hash_builder.hash_one(key))
     }
 
     pub fn insert(&self, key: K, value: V) {
-        let table = self.table.load();
-        let hash = self.hash(&key);
-
-        let value = Arc::new(value);
-        let f = (self.items.load(Ordering::Relaxed) as f32) / (table.buckets.len() * N) as f32;
-        let new_table =
-            match table
-                .hash_into(hash)
-                .insert(hash, key.clone(), value.clone(), true, f, 1)
-            {
+        self.raw_insert(self.hash(&key), key, value)
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.raw_remove(self.hash(key), |k| k == key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V, A>> {
+        self.raw_get(self.hash(key), |k| k == key)
+    }
+
+    /// Insert `key` with a precomputed `hash`. The caller must make sure
+    /// that `hash` is consistent with the one computed by `HashMap::hash`,
+    /// otherwise the key will not be found by the non-raw methods.
+    pub fn raw_insert(&self, hash: u64, key: K, value: V) {
+        self.insert_with_table(&self.table.load(), remap_hash(hash), key, value);
+    }
+
+    /// Remove the first entry with the given `hash` for which `is_match`
+    /// returns `true`.
+    pub fn raw_remove<F: FnMut(&K) -> bool>(&self, hash: u64, is_match: F) {
+        self.remove_with_table(&self.table.load(), remap_hash(hash), is_match);
+    }
+
+    /// Return the value of the first entry with the given `hash` for which
+    /// `is_match` returns `true`.
+    pub fn raw_get<F: FnMut(&K) -> bool>(&self, hash: u64, mut is_match: F) -> Option<Arc<V, A>> {
+        let hash = remap_hash(hash);
+        self.table.load_guard().get(hash, &mut is_match)
+    }
+
+    /// Make sure that at least `additional` more items can be inserted
+    /// without triggering a resize, unless other threads insert or resize
+    /// concurrently. A resize already in progress is helped to completion
+    /// first, and only then is the new table grown if it is still too small.
+    pub fn reserve(&self, additional: usize) {
+        let items = self.items.load(Ordering::Relaxed) as usize + additional;
+        let needed = ((items as f32 / (N as f32 * MIN_LOAD_FACTOR_FOR_RESIZE)) as usize + 1)
+            .next_power_of_two();
+
+        let mut table = self.table.load();
+        if let Some(resizer) = table.resizer.load() {
+            table = self.finish_resize(&table, &resizer);
+        }
+        if table.buckets.len() < needed {
+            let resizer = table.install_resizer(Resizer::new(needed, &self.alloc));
+            self.finish_resize(&table, &resizer);
+        }
+    }
+
+    /// Insert into `table`, which may be stale. Return `true` if a resize
+    /// was involved, in which case callers reusing `table` should reload it.
+    fn insert_with_table(
+        &self,
+        table: &Arc<Buckets<K, V, A>, A>,
+        hash: u64,
+        key: K,
+        value: V,
+    ) -> bool {
+        let value = Arc::new_in(value, self.alloc.clone());
+        let (mut table, mut resized) = (table.clone(), false);
+        loop {
+            let f = (self.items.load(Ordering::Relaxed) as f32) / (table.buckets.len() * N) as f32;
+            let bucket = table.hash_into(hash);
+            let resizer = match bucket.insert(hash, &key, &value, true, f, 1, table.allocator()) {
                 Ok(inserted) => {
                     if inserted {
                         self.items.fetch_add(1, Ordering::Relaxed);
                     }
-                    match table.resizer.load() {
-                        Some(resizer) => Buckets::resize_with_pending_update(
-                            &table,
-                            &resizer,
-                            hash,
-                            PendingUpdate::Reinsert(key, value),
-                            &self.items,
-                        ),
-                        None => return,
-                    }
+                    return resized;
                 }
-                Err(ResizeNeeded) => {
-                    let old_size = table.buckets.len();
-                    let new_size = 2 * old_size;
-                    let new_helper = Resizer::new(new_size, old_size);
-                    table.resizer.try_store(&None, Some(Arc::new(new_helper)));
-
-                    Buckets::resize_with_pending_update(
-                        &table,
-                        &table.resizer.load().unwrap(),
-                        hash,
-                        PendingUpdate::Insert(key, value),
-                        &self.items,
-                    )
+                Err(Retry::ResizeNeeded) => {
+                    let new_size = 2 * table.buckets.len();
+                    table.install_resizer(Resizer::new(new_size, &self.alloc))
                 }
+                // The bucket is being copied by a resize, which is installed.
+                Err(Retry::Moved) => table.resizer.load().unwrap(),
             };
-
-        if let Some(new_table) = new_table {
-            self.table.try_store(&table, Arc::new(new_table));
+            table = self.finish_resize(&table, &resizer);
+            resized = true;
         }
     }
 
-    pub fn remove(&self, key: &K) {
-        let table = self.table.load();
-        let hash = self.hash(&key);
-
-        table.hash_into(hash).remove(hash, key);
-
-        if let Some(resizer) = table.resizer.load() {
-            let new_table = Buckets::resize_with_pending_update(
-                &table,
-                &resizer,
-                hash,
-                PendingUpdate::Remove(key),
-                &self.items,
-            );
-
-            if let Some(new_table) = new_table {
-                self.table.try_store(&table, Arc::new(new_table));
+    /// Same as `insert_with_table`, for removals.
+    fn remove_with_table<F: FnMut(&K) -> bool>(
+        &self,
+        table: &Arc<Buckets<K, V, A>, A>,
+        hash: u64,
+        mut is_match: F,
+    ) -> bool {
+        let (mut table, mut resized) = (table.clone(), false);
+        loop {
+            match table.hash_into(hash).remove(hash, &mut is_match) {
+                Ok(()) => return resized,
+                Err(Moved) => {
+                    let resizer = table.resizer.load().unwrap();
+                    table = self.finish_resize(&table, &resizer);
+                    resized = true;
+                }
             }
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<Arc<V>> {
-        let table = self.table.load();
-        let hash = self.hash(&key);
-        table.hash_into(hash).get(hash, key)
+    /// Help `resizer` copy `table`, and replace `table` with the new table in
+    /// the map if it is still installed. Return the new table, to which
+    /// updates frozen out of `table` can be applied.
+    fn finish_resize(
+        &self,
+        table: &Arc<Buckets<K, V, A>, A>,
+        resizer: &Resizer<K, V, A>,
+    ) -> Arc<Buckets<K, V, A>, A> {
+        let new_table = table.resize(resizer, &self.items);
+        self.table.try_store(table, new_table.clone());
+        new_table
     }
 }
 
-struct Resizer<K, V> {
-    buckets: std::sync::Arc<[VirtualBucket<K, V>]>,
-    markers: Vec<AtomicU8>,
+/// A resize in progress: `table` is the table being filled, which replaces
+/// the old one once all its chunks have been copied.
+struct Resizer<K, V, A: Allocator> {
+    table: Arc<Buckets<K, V, A>, A>,
 }
 
-const CHUNK_SIZE: usize = 8;
-
-impl<K, V> Resizer<K, V> {
-    fn new(size: usize, old_size: usize) -> Self {
-        let chunks = (old_size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+impl<K, V, A: Allocator + Clone> Resizer<K, V, A> {
+    fn new(size: usize, alloc: &A) -> Self {
         Self {
-            buckets: VirtualBucket::alloc(size),
-            markers: (0..chunks).map(|_| AtomicU8::new(0)).collect(),
+            table: Arc::new_in(Buckets::new(size, alloc.clone()), alloc.clone()),
         }
     }
+}
+
+const CHUNK_SIZE: usize = 8;
+
+/// Set in the state of a chunk once a thread started copying it, so that
+/// other threads copy the chunks nobody claimed first.
+const CLAIMED: u32 = 1 << 31;
+/// Set in the state of a chunk once it has been copied, so that its entries
+/// must be read from the new table.
+const COPIED: u32 = 1 << 30;
 
-    fn hash_into(&self, hash: u64) -> &VirtualBucket<K, V> {
+struct Buckets<K, V, A: Allocator> {
+    buckets: Box<[VirtualBucket<K, V, A>], A>,
+    chunks: Vec<AtomicU32, A>,
+    resizer: NullableAtomicArc<Resizer<K, V, A>, A>,
+}
+
+impl<K, V, A: Allocator> Buckets<K, V, A> {
+    fn allocator(&self) -> &A {
+        Box::allocator(&self.buckets)
+    }
+
+    fn hash_into(&self, hash: u64) -> &VirtualBucket<K, V, A> {
         &self.buckets[(hash as usize) & (self.buckets.len() - 1)]
     }
-}
 
-struct Buckets<K, V> {
-    buckets: std::sync::Arc<[VirtualBucket<K, V>]>,
-    resizer: NullableAtomicArc<Resizer<K, V>>,
+    /// Return the indices of `hashes` sorted by the bucket they fall into,
+    /// so that a batch visits each bucket only once and in memory order.
+    /// The sort is stable, so that the last of duplicate keys wins.
+    fn probe_order(&self, hashes: &[u64]) -> alloc::vec::Vec<usize> {
+        let mask = self.buckets.len() - 1;
+        let mut order = (0..hashes.len()).collect::<alloc::vec::Vec<_>>();
+        order.sort_by_key(|&i| (hashes[i] as usize) & mask);
+        order
+    }
 }
 
-impl<K, V> Buckets<K, V> {
-    fn new_with_buckets(buckets: std::sync::Arc<[VirtualBucket<K, V>]>) -> Self {
+impl<K, V, A: Allocator + Clone> Buckets<K, V, A> {
+    fn new(size: usize, alloc: A) -> Self {
+        let mut chunks = Vec::with_capacity_in(size.div_ceil(CHUNK_SIZE), alloc.clone());
+        chunks.extend((0..size.div_ceil(CHUNK_SIZE)).map(|_| AtomicU32::new(0)));
         Self {
-            buckets,
+            buckets: VirtualBucket::alloc(size, alloc),
+            chunks,
             resizer: AtomicArc::new_nullable(None),
         }
     }
 
-    fn new(size: usize) -> Self {
-        Self::new_with_buckets(VirtualBucket::alloc(size))
+    /// Start a resize with `resizer`, unless another one won the race, and
+    /// return the resizer in use. A resizer is never uninstalled.
+    fn install_resizer(&self, resizer: Resizer<K, V, A>) -> Arc<Resizer<K, V, A>, A> {
+        let resizer = Arc::new_in(resizer, self.allocator().clone());
+        match self.resizer.compare_exchange(&None, Some(resizer.clone())) {
+            Ok(..) => resizer,
+            Err((actual, _)) => actual.unwrap(),
+        }
     }
+}
 
-    fn hash_into(&self, hash: u64) -> &VirtualBucket<K, V> {
-        &self.buckets[(hash as usize) & (self.buckets.len() - 1)]
+impl<K: Eq + Clone, V, A: Allocator> Buckets<K, V, A> {
+    /// Return the value of the first entry with the given `hash` for which
+    /// `is_match` returns `true`, looking into the tables replacing this one
+    /// if the bucket is being copied by a resize.
+    fn get<F: FnMut(&K) -> bool>(&self, hash: u64, is_match: &mut F) -> Option<Arc<V, A>> {
+        self.lookup(hash, is_match).flatten()
+    }
+
+    /// Same as `get`, returning `None` if the key has no entry, and the
+    /// value of its entry otherwise.
+    fn lookup<F: FnMut(&K) -> bool>(
+        &self,
+        hash: u64,
+        is_match: &mut F,
+    ) -> Option<Option<Arc<V, A>>> {
+        match self.hash_into(hash).get(hash, &mut *is_match) {
+            Lookup::Found(value) => Some(value),
+            Lookup::Absent => None,
+            // A frozen value is only put into the new table by the resize,
+            // or replaced there once the resize is over.
+            Lookup::Moved(frozen) => {
+                let resizer = self.resizer.load().unwrap();
+                resizer.table.lookup(hash, is_match).or(frozen.map(Some))
+            }
+        }
     }
 }
 
-enum PendingUpdate<'a, K, V> {
-    Reinsert(K, Arc<V>),
-    Insert(K, Arc<V>),
-    Remove(&'a K),
+impl<K: Clone, V, A: Allocator> Buckets<K, V, A> {
+    /// Push the entries of the bucket at `index` to `out`, looking into the
+    /// buckets replacing it if it has already been copied by a resize.
+    fn collect_bucket(&self, index: usize, out: &mut alloc::vec::Vec<(K, Arc<V, A>)>) {
+        if self.chunks[index / CHUNK_SIZE].load(Ordering::Acquire) & COPIED == 0 {
+            self.buckets[index].collect_into(out);
+            return;
+        }
+
+        // Tables only grow, by a power of two: the entries of the bucket are
+        // spread over the buckets of the new table with the same low bits.
+        let table = self.resizer.load().unwrap().table.clone();
+        for j in (index..table.buckets.len()).step_by(self.buckets.len()) {
+            table.collect_bucket(j, out);
+        }
+    }
 }
 
-impl<K: Eq + Clone, V> Buckets<K, V> {
-    fn copy_chunk_to(&self, chunk: usize, dst: &Resizer<K, V>) -> u64 {
-        let mut removed = 0;
+impl<K, V, A: Allocator> Drop for Buckets<K, V, A> {
+    fn drop(&mut self) {
+        for bucket in self.buckets.iter() {
+            unsafe { bucket.free(self.allocator()) };
+        }
+    }
+}
+
+impl<K: Eq + Clone, V, A: Allocator> Buckets<K, V, A> {
+    /// Insert a value frozen by a resize, unless the key already has an
+    /// entry. This table is only resized once the previous resize is over,
+    /// after which every frozen value has been put by some thread: a bucket
+    /// being copied means that the put comes from a copy which fell behind,
+    /// and is dropped rather than forwarded to the next table, where it
+    /// would bring back an entry removed since, without counting it.
+    fn put_if_absent(&self, hash: u64, key: &K, value: &Arc<V, A>) {
+        match self
+            .hash_into(hash)
+            .insert(hash, key, value, false, 0., 1, self.allocator())
+        {
+            Ok(..) | Err(Retry::Moved) => (),
+            Err(Retry::ResizeNeeded) => unreachable!("load factor = 0."),
+        }
+    }
+}
+
+impl<K: Eq + Clone, V, A: Allocator + Clone> Buckets<K, V, A> {
+    /// Copy the buckets of `chunk` to the table of `resizer`, and mark the
+    /// chunk as copied.
+    fn copy_chunk_to(&self, chunk: usize, resizer: &Resizer<K, V, A>, items: &AtomicU64) {
         let lower = chunk * CHUNK_SIZE;
-        let upper = std::cmp::min(lower + CHUNK_SIZE, self.buckets.len());
+        let upper = core::cmp::min(lower + CHUNK_SIZE, self.buckets.len());
+        let mut removed = 0;
         for j in lower..upper {
-            removed += self.buckets[j].copy_to(dst);
+            removed += self.buckets[j].copy_to(&resizer.table);
         }
-        removed
+        items.fetch_sub(removed, Ordering::Relaxed);
+        self.chunks[chunk].fetch_or(COPIED, Ordering::Release);
     }
 
-    fn resize_with_pending_update(
-        old_table: &Arc<Buckets<K, V>>,
-        resizer: &Arc<Resizer<K, V>>,
-        hash: u64,
-        update: PendingUpdate<'_, K, V>,
-        items: &AtomicU64,
-    ) -> Option<Buckets<K, V>> {
-        let virtual_bucket = resizer.hash_into(hash);
-        match update {
-            PendingUpdate::Insert(key, value) => {
-                match virtual_bucket.insert(hash, key, value, true, 0., 1) {
-                    Ok(true) => items.fetch_add(1, Ordering::Relaxed),
-                    Ok(false) => 0,
-                    Err(..) => panic!("load factor = 0."),
-                };
-            }
-            PendingUpdate::Reinsert(key, value) => {
-                assert!(virtual_bucket.insert(hash, key, value, true, 0., 1).is_ok());
-            }
-            PendingUpdate::Remove(key) => {
-                virtual_bucket.remove(hash, key);
+    /// Copy this table to the table of `resizer`, and return the latter.
+    /// Chunks which nobody claimed yet are copied first, then the ones still
+    /// being copied by other threads are copied again: copies only put
+    /// frozen values, so they can overlap, and we never wait for the copy
+    /// of a thread which may not be running.
+    fn resize(&self, resizer: &Resizer<K, V, A>, items: &AtomicU64) -> Arc<Buckets<K, V, A>, A> {
+        let mut claimed_by_others = false;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if chunk.fetch_or(CLAIMED, Ordering::AcqRel) & CLAIMED == 0 {
+                self.copy_chunk_to(i, resizer, items);
+            } else {
+                claimed_by_others = true;
             }
         }
 
-        for (chunk, marker) in resizer.markers.iter().enumerate() {
-            match marker.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Relaxed) {
-                Ok(..) => {
-                    items.fetch_sub(old_table.copy_chunk_to(chunk, &resizer), Ordering::Relaxed);
-                    marker.store(2, Ordering::Release);
+        if claimed_by_others {
+            for (i, chunk) in self.chunks.iter().enumerate() {
+                if chunk.load(Ordering::Acquire) & COPIED == 0 {
+                    self.copy_chunk_to(i, resizer, items);
                 }
-                Err(..) => continue,
-            }
-        }
-
-        for marker in resizer.markers.iter() {
-            if marker.load(Ordering::Acquire) != 2 {
-                return None;
             }
         }
-
-        Some(Buckets::new_with_buckets(resizer.buckets.clone()))
+        resizer.table.clone()
     }
 }
@@ -0,0 +1,83 @@
+//! Parallel iteration and bulk loading with rayon.
+
+use super::{Buckets, HashMap, CHUNK_SIZE};
+use crate::atomic_arc::{Allocator, Arc, Global};
+use core::hash::{BuildHasher, Hash};
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::iter::{
+    FromParallelIterator, IndexedParallelIterator, IntoParallelIterator, ParallelExtend,
+    ParallelIterator,
+};
+
+impl<K: Clone, V, S, A: Allocator> HashMap<K, V, S, A> {
+    /// Return a parallel iterator over the entries of the map, with the same
+    /// guarantees as `HashMap::iter`. The table is split into ranges of
+    /// buckets, which are no smaller than the chunks copied by resizes.
+    pub fn par_iter(&self) -> ParIter<K, V, A> {
+        ParIter {
+            table: self.table.load(),
+        }
+    }
+}
+
+/// A parallel iterator over the entries of a `HashMap`, see
+/// `HashMap::par_iter`.
+pub struct ParIter<K, V, A: Allocator = Global> {
+    table: Arc<Buckets<K, V, A>, A>,
+}
+
+impl<K, V, A> ParallelIterator for ParIter<K, V, A>
+where
+    K: Clone + Send + Sync,
+    V: Send + Sync,
+    A: Allocator + Send + Sync,
+{
+    type Item = (K, Arc<V, A>);
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        let table = &*self.table;
+        (0..table.buckets.len())
+            .into_par_iter()
+            .with_min_len(CHUNK_SIZE)
+            .flat_map_iter(|index| {
+                let mut entries = Vec::new();
+                table.collect_bucket(index, &mut entries);
+                entries
+            })
+            .drive_unindexed(consumer)
+    }
+}
+
+/// Items are inserted concurrently as they are produced, after reserving
+/// room for all of them when the iterator knows its length upfront. Unlike
+/// with `Extend`, which of duplicate keys wins is unspecified.
+impl<K, V, S, A> ParallelExtend<(K, V)> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher + Sync,
+    A: Allocator + Clone + Send + Sync,
+{
+    fn par_extend<I: IntoParallelIterator<Item = (K, V)>>(&mut self, par_iter: I) {
+        let par_iter = par_iter.into_par_iter();
+        let map = &*self;
+        if let Some(len) = par_iter.opt_len() {
+            map.reserve(len);
+        }
+        par_iter.for_each(|(key, value)| map.insert(key, value));
+    }
+}
+
+impl<K, V, S, A> FromParallelIterator<(K, V)> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher + Default + Sync,
+    A: Allocator + Clone + Default + Send + Sync,
+{
+    fn from_par_iter<I: IntoParallelIterator<Item = (K, V)>>(par_iter: I) -> Self {
+        let mut map = Self::with_hasher_in(S::default(), A::default());
+        map.par_extend(par_iter);
+        map
+    }
+}
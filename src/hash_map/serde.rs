@@ -0,0 +1,68 @@
+//! `Serialize` and `Deserialize` for `HashMap`, as a map.
+
+use super::HashMap;
+use crate::atomic_arc::Allocator;
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash};
+use core::marker::PhantomData;
+use core::{cmp, fmt, mem};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The most bytes of entries reserved upfront from the size hint of a
+/// deserializer, which may come from untrusted input, as serde does for its
+/// own collections.
+const MAX_PREALLOC_BYTES: usize = 1024 * 1024;
+
+/// The map is serialized from a snapshot taken with `HashMap::iter`, so
+/// entries updated concurrently may or may not be part of it. The snapshot
+/// is taken upfront, so that formats requiring a length get the exact one.
+impl<K, V, S, A> Serialize for HashMap<K, V, S, A>
+where
+    K: Clone + Serialize,
+    V: Serialize,
+    A: Allocator,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let entries = self.iter().collect::<Vec<_>>();
+        serializer.collect_map(entries.iter().map(|(key, value)| (key, &**value)))
+    }
+}
+
+impl<'de, K, V, S, A> Deserialize<'de> for HashMap<K, V, S, A>
+where
+    K: Eq + Hash + Clone + Deserialize<'de>,
+    V: Deserialize<'de>,
+    S: BuildHasher + Default,
+    A: Allocator + Clone + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MapVisitor<K, V, S, A: Allocator>(PhantomData<HashMap<K, V, S, A>>);
+
+        impl<'de, K, V, S, A> Visitor<'de> for MapVisitor<K, V, S, A>
+        where
+            K: Eq + Hash + Clone + Deserialize<'de>,
+            V: Deserialize<'de>,
+            S: BuildHasher + Default,
+            A: Allocator + Clone + Default,
+        {
+            type Value = HashMap<K, V, S, A>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a map")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+                let map = HashMap::with_hasher_in(S::default(), A::default());
+                let max = MAX_PREALLOC_BYTES / cmp::max(mem::size_of::<(K, V)>(), 1);
+                map.reserve(cmp::min(access.size_hint().unwrap_or(0), max));
+                while let Some((key, value)) = access.next_entry()? {
+                    map.insert(key, value);
+                }
+                Ok(map)
+            }
+        }
+
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
@@ -0,0 +1,297 @@
+//! A binary snapshot format, to dump a map to disk and restore it.
+//!
+//! A snapshot starts with a 32 bytes header, with all integers in little
+//! endian:
+//!
+//! - the magic `HMAPSNAP`,
+//! - the format version, as a `u32`,
+//! - the checksum used, as a `u32`, see `Checksum`,
+//! - the identity of the hasher, as a `u64`, see `HashMap::snapshot`,
+//! - the number of entries, as a `u64`.
+//!
+//! Each entry is then written as its hash, as a `u64`, followed by its key
+//! and its value as encoded by a `Codec`, each prefixed by its length as a
+//! `u32`. If a checksum is used, it follows the entries as a `u64` and covers
+//! everything before it, header included.
+
+use super::HashMap;
+use crate::atomic_arc::{Allocator, Global};
+use core::convert::{TryFrom, TryInto};
+use core::hash::{BuildHasher, Hash};
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 8] = *b"HMAPSNAP";
+const VERSION: u32 = 1;
+/// The value hashed to identify the hasher of a map.
+const HASHER_PROBE: u64 = 0x6861_7368_5f6d_6170;
+
+/// Converts keys and values to and from the bytes of a snapshot.
+pub trait Codec<K, V> {
+    fn encode_key(&self, key: &K, out: &mut Vec<u8>);
+    fn encode_value(&self, value: &V, out: &mut Vec<u8>);
+    /// Decode a key from the exact bytes written by `encode_key`. Errors
+    /// are returned as is by `HashMap::restore`, and should preferably be of
+    /// the `io::ErrorKind::InvalidData` kind.
+    fn decode_key(&self, bytes: &[u8]) -> io::Result<K>;
+    fn decode_value(&self, bytes: &[u8]) -> io::Result<V>;
+}
+
+/// The checksum written at the end of a snapshot, if any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    None,
+    /// The 64 bits FNV-1a hash of the snapshot. It catches truncation and
+    /// corruption, not tampering.
+    Fnv1a,
+}
+
+impl Checksum {
+    fn from_u32(value: u32) -> io::Result<Self> {
+        match value {
+            0 => Ok(Checksum::None),
+            1 => Ok(Checksum::Fnv1a),
+            _ => Err(invalid_data(format!("unknown snapshot checksum {}", value))),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Fnv1a => 1,
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A running FNV-1a hash.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// A writer or reader of a snapshot, hashing what goes through it when a
+/// checksum is used.
+struct Stream<T> {
+    inner: T,
+    checksum: Option<Fnv1a>,
+}
+
+impl<T> Stream<T> {
+    fn new(inner: T, checksum: Checksum) -> Self {
+        Stream {
+            inner,
+            checksum: (checksum == Checksum::Fnv1a).then(Fnv1a::new),
+        }
+    }
+
+    fn hash(&mut self, bytes: &[u8]) {
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(bytes);
+        }
+    }
+}
+
+impl<W: Write> Stream<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hash(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    /// Write the checksum, if any, and flush.
+    fn write_checksum(mut self) -> io::Result<()> {
+        if let Some(checksum) = self.checksum.take() {
+            self.inner.write_all(&checksum.0.to_le_bytes())?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Stream<R> {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(bytes)?;
+        self.hash(bytes);
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.read(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a length prefixed record into `buffer`. The buffer grows with
+    /// the bytes actually read, so that a corrupted length does not allocate
+    /// more than the snapshot holds.
+    fn read_record(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let len = self.read_u32()?;
+        buffer.clear();
+        (&mut self.inner).take(len as u64).read_to_end(buffer)?;
+        if buffer.len() != len as usize {
+            return Err(invalid_data(format!(
+                "snapshot record of {} bytes truncated to {} bytes",
+                len,
+                buffer.len()
+            )));
+        }
+        self.hash(buffer);
+        Ok(())
+    }
+
+    /// Read the checksum, if any, and check it.
+    fn read_checksum(mut self) -> io::Result<()> {
+        if let Some(checksum) = self.checksum.take() {
+            let mut bytes = [0; 8];
+            self.inner.read_exact(&mut bytes)?;
+            if u64::from_le_bytes(bytes) != checksum.0 {
+                return Err(invalid_data("snapshot checksum mismatch".into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append `record` to `out`, prefixed by its length.
+fn push_record(out: &mut Vec<u8>, record: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    record(out);
+    let len = u32::try_from(out.len() - start - 4).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshot record longer than u32::MAX bytes",
+        )
+    })?;
+    out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+impl<K: Eq + Hash + Clone, V, S: BuildHasher, A: Allocator + Clone> HashMap<K, V, S, A> {
+    /// Write a snapshot of the map to `writer`, see the module documentation
+    /// for the format. `writer` is written to record by record, so it should
+    /// be buffered.
+    ///
+    /// The snapshot is taken with `HashMap::iter` before anything is
+    /// written, and can be taken while the map is being modified: entries
+    /// which are in the map for the whole call are written exactly once,
+    /// with one of the values they had during the call, and entries which
+    /// are inserted or removed concurrently may or may not be written. It is
+    /// not a point in time view of the map.
+    ///
+    /// The hashes of the entries are written too, so that a map restored
+    /// with the same hasher checks them against the keys it decodes. Hashers
+    /// are identified by the hash they give to a fixed value, so seeded
+    /// hashers only match if they share their seed.
+    pub fn snapshot<W, C>(&self, writer: W, codec: &C, checksum: Checksum) -> io::Result<()>
+    where
+        W: Write,
+        C: Codec<K, V>,
+    {
+        let entries = self.iter().collect::<Vec<_>>();
+        let mut stream = Stream::new(writer, checksum);
+        stream.write(&MAGIC)?;
+        stream.write(&VERSION.to_le_bytes())?;
+        stream.write(&checksum.to_u32().to_le_bytes())?;
+        stream.write(&self.hash_builder.hash_one(HASHER_PROBE).to_le_bytes())?;
+        stream.write(&(entries.len() as u64).to_le_bytes())?;
+
+        let mut record = Vec::new();
+        for (key, value) in &entries {
+            record.clear();
+            record.extend_from_slice(&self.hash(key).to_le_bytes());
+            push_record(&mut record, |out| codec.encode_key(key, out))?;
+            push_record(&mut record, |out| codec.encode_value(value, out))?;
+            stream.write(&record)?;
+        }
+        stream.write_checksum()
+    }
+
+    /// Restore a map written by `HashMap::snapshot`, hashing keys with
+    /// `hash_builder` and allocating with `alloc`.
+    ///
+    /// The entries are decoded into a buffer which grows as they are read,
+    /// so that a corrupted count gives an error rather than a large
+    /// allocation. They are only inserted once the checksum, if any, has
+    /// been verified, into a table sized for all of them. Keys are hashed
+    /// again: with the same hasher, an entry whose hash differs from the
+    /// one written is an error.
+    pub fn restore_with_hasher_in<R, C>(
+        mut reader: R,
+        codec: &C,
+        hash_builder: S,
+        alloc: A,
+    ) -> io::Result<Self>
+    where
+        R: Read,
+        C: Codec<K, V>,
+    {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(invalid_data("not a map snapshot".into()));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {}",
+                version
+            )));
+        }
+        let checksum = Checksum::from_u32(u32::from_le_bytes(header[12..].try_into().unwrap()))?;
+        let mut stream = Stream::new(reader, checksum);
+        stream.hash(&header);
+
+        let map = Self::with_hasher_in(hash_builder, alloc);
+        let same_hasher = stream.read_u64()? == map.hash_builder.hash_one(HASHER_PROBE);
+        let len = stream.read_u64()?;
+
+        let (mut entries, mut buffer) = (Vec::new(), Vec::new());
+        for _ in 0..len {
+            let written_hash = stream.read_u64()?;
+            stream.read_record(&mut buffer)?;
+            let key = codec.decode_key(&buffer)?;
+            stream.read_record(&mut buffer)?;
+            let value = codec.decode_value(&buffer)?;
+            let hash = map.hash(&key);
+            if same_hasher && hash != written_hash {
+                return Err(invalid_data(format!(
+                    "snapshot entry hash {:#x} does not match its key",
+                    written_hash
+                )));
+            }
+            entries.push((hash, key, value));
+        }
+        stream.read_checksum()?;
+
+        map.reserve(entries.len());
+        for (hash, key, value) in entries {
+            map.raw_insert(hash, key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> HashMap<K, V> {
+    /// Restore a map written by `HashMap::snapshot`, see
+    /// `HashMap::restore_with_hasher_in`.
+    pub fn restore<R: Read, C: Codec<K, V>>(reader: R, codec: &C) -> io::Result<Self> {
+        Self::restore_with_hasher_in(reader, codec, Default::default(), Global)
+    }
+}
@@ -1,77 +1,243 @@
-use super::{Resizer, DEPTH_TRESHOLD, MIN_LOAD_FACTOR_FOR_RESIZE, N};
-use crate::atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
-use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use super::{Buckets, DEPTH_TRESHOLD, MIN_LOAD_FACTOR_FOR_RESIZE, N};
+use crate::atomic_arc::{Allocator, Arc, AtomicArc, MarkedAtomicArc};
+use crate::sync::{AtomicPtr, AtomicU64, Ordering};
+use allocator_api2::boxed::Box;
+use allocator_api2::vec::Vec;
+use core::marker::PhantomData;
 
-struct Entry<K, V> {
+struct Entry<K, V, A: Allocator> {
+    hash: u64,
     key: K,
-    value: NullableAtomicArc<V>,
+    value: MarkedAtomicArc<V, A>,
 }
 
+/// Number of words of tags, each holding the tags of 8 slots in its bytes,
+/// from the least significant one.
+const TAG_WORDS: usize = 2;
+
+/// Each slot is guarded by a one byte tag holding the 7 highest bits of the
+/// hash, with the highest bit of the tag set so that an empty slot (tag 0)
+/// never matches. Full hashes live in the entries and are only checked when
+/// the tag matches. With `N = 13`, the tags, `next` and the entries fill
+/// exactly two cache lines, and negative lookups only touch the first one.
+/// Tags are probed a word at a time, see `match_tag`.
+///
+/// Entries and chained buckets are allocated with the allocator of the
+/// table, which is not stored in each bucket: they are freed by the table
+/// through `VirtualBucket::free`.
 #[repr(C)]
 #[repr(align(64))]
-pub(super) struct VirtualBucket<K, V> {
-    hashes: [AtomicU64; N],
-    next: AtomicPtr<VirtualBucket<K, V>>,
-    entries: [AtomicPtr<Entry<K, V>>; N],
+pub(super) struct VirtualBucket<K, V, A: Allocator> {
+    tags: [AtomicU64; TAG_WORDS],
+    next: AtomicPtr<VirtualBucket<K, V, A>>,
+    entries: [AtomicPtr<Entry<K, V, A>>; N],
+    // The atomic pointers would otherwise make buckets `Send` and `Sync`
+    // whatever the entries they own.
+    _marker: PhantomData<Entry<K, V, A>>,
+}
+
+/// The tag of the slots frozen empty by a resize. The tags of hashes are
+/// above it, so that the slots of a word are frozen by setting the highest
+/// bit of all its tags, without changing the ones already claimed.
+const FROZEN_TAG: u8 = 0x80;
+
+fn tag(hash: u64) -> u8 {
+    ((hash >> 57) as u8 | FROZEN_TAG).max(FROZEN_TAG + 1)
 }
 
-impl<K, V> Default for VirtualBucket<K, V> {
+impl<K, V, A: Allocator> Default for VirtualBucket<K, V, A> {
     fn default() -> Self {
         Self {
-            hashes: Default::default(),
+            tags: Default::default(),
             next: Default::default(),
             entries: Default::default(),
+            _marker: PhantomData,
         }
     }
 }
 
-impl<K, V> VirtualBucket<K, V> {
-    pub(super) fn alloc(size: usize) -> std::sync::Arc<[VirtualBucket<K, V>]> {
-        (0..size)
-            .map(|_| Default::default())
-            .collect::<Vec<Self>>()
-            .into()
+impl<K, V, A: Allocator> VirtualBucket<K, V, A> {
+    pub(super) fn alloc(size: usize, alloc: A) -> Box<[Self], A> {
+        let mut buckets = Vec::with_capacity_in(size, alloc);
+        buckets.extend((0..size).map(|_| Self::default()));
+        buckets.into_boxed_slice()
+    }
+
+    /// Hint the CPU to fetch the first cache line of this bucket, which
+    /// holds all its tags. This is a no-op on non-x86_64 targets.
+    #[inline]
+    pub(super) fn prefetch(&self) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch(self as *const Self as *const i8, _MM_HINT_T0);
+        }
+    }
+
+    fn find_tag(&self, tag: u8, start: usize) -> Option<usize> {
+        match self.match_tag(tag) >> start << start {
+            0 => None,
+            mask => Some(mask.trailing_zeros() as usize),
+        }
     }
 
-    fn find_hash(&self, hash: u64, start: usize) -> Option<usize> {
-        for j in start..N {
-            if self.hashes[j].load(Ordering::Relaxed) == hash {
-                return Some(j);
+    /// Set the tag of slot `j` to `tag` if it is empty, and return whether
+    /// the slot is now tagged with `tag`, or `Moved` if the slot was frozen
+    /// empty by a resize. Tags are claimed by a CAS on their whole word,
+    /// which only fails if another slot of the word changed concurrently.
+    fn claim_tag(&self, j: usize, tag: u8) -> Result<bool, Moved> {
+        let (word, shift) = (&self.tags[j / 8], j % 8 * 8);
+        let mut tags = word.load(Ordering::Relaxed);
+        loop {
+            match (tags >> shift) as u8 {
+                0 => (),
+                FROZEN_TAG => return Err(Moved),
+                actual_tag => return Ok(actual_tag == tag),
+            }
+            match word.compare_exchange_weak(
+                tags,
+                tags | (tag as u64) << shift,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => return Ok(true),
+                Err(actual_tags) => tags = actual_tags,
             }
         }
-        None
+    }
+
+    /// Set the tag of all the empty slots to `FROZEN_TAG`, a word at a time,
+    /// and return the tags, which cannot change anymore.
+    fn freeze_tags(&self) -> [u64; TAG_WORDS] {
+        const FROZEN: u64 = FROZEN_TAG as u64 * 0x0101_0101_0101_0101;
+        let mut tags = [0; TAG_WORDS];
+        for (word, tags) in self.tags.iter().zip(&mut tags) {
+            *tags = word.fetch_or(FROZEN, Ordering::AcqRel) | FROZEN;
+        }
+        tags
+    }
+
+    /// Return a bitmask of the slots whose tag is equal to `tag`. Each word
+    /// is compared to `tag` bytewise with integer arithmetic, so there is no
+    /// per-slot branch and no load wider than an atomic. This is also the
+    /// probe of x86_64 targets without SSE2, such as kernels.
+    #[cfg(any(
+        not(all(target_arch = "x86_64", target_feature = "sse2")),
+        feature = "scalar-probe"
+    ))]
+    fn match_tag(&self, tag: u8) -> u32 {
+        const LOW: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+        let mut mask = 0;
+        for (i, word) in self.tags.iter().enumerate() {
+            // Zero the bytes equal to `tag`, then set the highest bit of
+            // exactly those bytes, without carries between bytes.
+            let x = word.load(Ordering::Relaxed) ^ (tag as u64 * 0x0101_0101_0101_0101);
+            let found = !(((x & LOW) + LOW) | x | LOW);
+            // Gather the highest bit of each byte into the highest byte.
+            let bits = ((found >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u32;
+            mask |= bits << (i * 8);
+        }
+        mask & ((1 << N) - 1)
+    }
+
+    /// Both words are loaded atomically, then compared to `tag` in a single
+    /// SSE2 register, so the vector compare never reads shared memory.
+    #[cfg(all(
+        target_arch = "x86_64",
+        target_feature = "sse2",
+        not(feature = "scalar-probe")
+    ))]
+    fn match_tag(&self, tag: u8) -> u32 {
+        use core::arch::x86_64::*;
+
+        let low = self.tags[0].load(Ordering::Relaxed);
+        let high = self.tags[1].load(Ordering::Relaxed);
+        unsafe {
+            let tags = _mm_set_epi64x(high as i64, low as i64);
+            let eq = _mm_cmpeq_epi8(tags, _mm_set1_epi8(tag as i8));
+            _mm_movemask_epi8(eq) as u32 & ((1 << N) - 1)
+        }
     }
 }
 
-pub(super) struct ResizeNeeded;
+// Negative lookups rely on the tags being at the start of the bucket, and on
+// the bucket not spilling over two cache lines. `loom` atomics are bigger.
+#[cfg(not(loom))]
+const _: () = assert!(
+    N <= TAG_WORDS * 8
+        && core::mem::size_of::<VirtualBucket<(), (), crate::atomic_arc::Global>>() == 128
+);
 
-impl<K: Eq, V> VirtualBucket<K, V> {
+/// Why an insert could not be applied to a bucket.
+pub(super) enum Retry {
+    /// The table is too full, and must be resized first.
+    ResizeNeeded,
+    /// The bucket is being copied by a resize: the update must be applied to
+    /// the new table, once the resize is over.
+    Moved,
+}
+
+/// A removal could not be applied to a bucket being copied by a resize.
+pub(super) struct Moved;
+
+/// The result of a lookup in a bucket.
+pub(super) enum Lookup<V, A: Allocator> {
+    /// The key has an entry, whose value may have been removed.
+    Found(Option<Arc<V, A>>),
+    Absent,
+    /// The bucket is being copied by a resize, and the key must be looked up
+    /// in the new table. If it is not there yet, its value is the one frozen
+    /// in this bucket, if any.
+    Moved(Option<Arc<V, A>>),
+}
+
+/// Stored by a resize in the slots which are tagged but still empty, and in
+/// the missing `next` of the buckets it copies, so that nothing can be
+/// inserted there anymore. It is never dereferenced.
+fn frozen<T>() -> *mut T {
+    core::ptr::NonNull::dangling().as_ptr()
+}
+
+impl<K: Eq + Clone, V, A: Allocator> VirtualBucket<K, V, A> {
+    /// Insert into this bucket, allocating entries and chained buckets with
+    /// `alloc`, which must be the allocator of the table. The key is only
+    /// cloned if a new entry is allocated. If `is_new_item` is `false`, an
+    /// existing entry for the key is left untouched, even if it is frozen.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn insert(
         &self,
         hash: u64,
-        mut key: K,
-        mut value: Arc<V>,
+        key: &K,
+        value: &Arc<V, A>,
         is_new_item: bool,
         load_factor: f32,
         depth: i32,
-    ) -> Result<bool, ResizeNeeded> {
+        alloc: &A,
+    ) -> Result<bool, Retry> {
+        let tag = tag(hash);
         for j in 0..N {
+            // A tag never changes once set, so a slot claimed for another tag
+            // is skipped without touching its entry. The key would have gone
+            // into a frozen slot, so it is not further in the bucket.
+            match self.claim_tag(j, tag) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(Moved) => return Err(Retry::Moved),
+            }
+
             let mut entry = self.entries[j].load(Ordering::SeqCst);
             if entry.is_null() {
-                match self.hashes[j].compare_exchange(0, hash, Ordering::AcqRel, Ordering::Relaxed)
-                {
-                    Ok(..) => (),
-                    Err(actual_hash) if actual_hash == hash => (),
-                    Err(..) => continue,
-                }
-
-                let new_entry = Box::into_raw(Box::new(Entry {
-                    key,
-                    value: AtomicArc::new_nullable(Some(value)),
-                }));
+                let new_entry = Box::into_raw(Box::new_in(
+                    Entry {
+                        hash,
+                        key: key.clone(),
+                        value: AtomicArc::new_marked(Some(value.clone())),
+                    },
+                    alloc,
+                ));
 
                 match self.entries[j].compare_exchange(
-                    std::ptr::null_mut(),
+                    core::ptr::null_mut(),
                     new_entry,
                     Ordering::AcqRel,
                     Ordering::Acquire,
@@ -79,119 +245,188 @@ impl<K: Eq, V> VirtualBucket<K, V> {
                     Ok(..) => return Ok(true),
                     Err(actual_entry) => {
                         entry = actual_entry;
-                        let failed_entry = unsafe { Box::from_raw(new_entry) };
-                        key = failed_entry.key;
-                        value = failed_entry.value.load().unwrap();
+                        drop(unsafe { Box::from_raw_in(new_entry, alloc) });
                     }
                 }
             }
 
-            assert!(!entry.is_null());
+            // Same as for a frozen tag.
+            if entry == frozen() {
+                return Err(Retry::Moved);
+            }
             let entry = unsafe { &*entry };
 
-            if self.hashes[j].load(Ordering::SeqCst) != hash || entry.key != key {
+            if entry.hash != hash || entry.key != *key {
                 continue;
             } else if !is_new_item {
                 return Ok(false);
             }
-            entry.value.store(Some(value));
-            return Ok(false);
+            return match entry.value.swap_unmarked(Some(value.clone())) {
+                Ok(..) => Ok(false),
+                Err(..) => Err(Retry::Moved),
+            };
         }
 
         if load_factor >= MIN_LOAD_FACTOR_FOR_RESIZE && depth >= DEPTH_TRESHOLD {
-            return Err(ResizeNeeded);
+            return Err(Retry::ResizeNeeded);
         }
 
         let mut next_ptr = self.next.load(Ordering::SeqCst);
         if next_ptr.is_null() {
-            let new_next = Box::into_raw(Box::new(VirtualBucket::default()));
+            let new_next = Box::into_raw(Box::new_in(VirtualBucket::default(), alloc));
             match self.next.compare_exchange(
                 next_ptr,
                 new_next,
                 Ordering::AcqRel,
                 Ordering::Acquire,
             ) {
-                Ok(ptr) => next_ptr = ptr,
-                Err(ptr) => next_ptr = ptr,
+                Ok(..) => next_ptr = new_next,
+                Err(ptr) => {
+                    drop(unsafe { Box::from_raw_in(new_next, alloc) });
+                    next_ptr = ptr;
+                }
             };
         }
 
-        assert!(!next_ptr.is_null());
-        unsafe { &*next_ptr }.insert(hash, key, value, is_new_item, load_factor, depth + 1)
+        if next_ptr == frozen() {
+            return Err(Retry::Moved);
+        }
+        unsafe { &*next_ptr }.insert(hash, key, value, is_new_item, load_factor, depth + 1, alloc)
     }
 
-    pub(super) fn remove(&self, hash: u64, key: &K) {
-        let mut start = 0;
-        while let Some(pos) = self.find_hash(hash, start) {
+    pub(super) fn remove<F: FnMut(&K) -> bool>(
+        &self,
+        hash: u64,
+        mut is_match: F,
+    ) -> Result<(), Moved> {
+        let (tag, mut start) = (tag(hash), 0);
+        while let Some(pos) = self.find_tag(tag, start) {
             let entry = self.entries[pos].load(Ordering::SeqCst);
-            if !entry.is_null() && unsafe { (*entry).key == *key } {
-                unsafe { (*entry).value.store(None) };
-                return;
+            // Same as in `insert`.
+            if entry == frozen() {
+                return Err(Moved);
+            }
+            if !entry.is_null() && unsafe { (*entry).hash == hash && is_match(&(*entry).key) } {
+                return match unsafe { (*entry).value.swap_unmarked(None) } {
+                    Ok(..) => Ok(()),
+                    Err(..) => Err(Moved),
+                };
             }
             start = pos + 1;
         }
 
-        let next_ptr = self.next.load(Ordering::SeqCst);
-        if !next_ptr.is_null() {
-            unsafe { &*next_ptr }.remove(hash, key);
+        match self.next.load(Ordering::SeqCst) {
+            next_ptr if next_ptr.is_null() => Ok(()),
+            next_ptr if next_ptr == frozen() => Err(Moved),
+            next_ptr => unsafe { &*next_ptr }.remove(hash, is_match),
         }
     }
 
-    pub(super) fn get(&self, hash: u64, key: &K) -> Option<Arc<V>> {
-        let mut start = 0;
-        while let Some(pos) = self.find_hash(hash, start) {
+    pub(super) fn get<F: FnMut(&K) -> bool>(&self, hash: u64, mut is_match: F) -> Lookup<V, A> {
+        let (tag, mut start) = (tag(hash), 0);
+        while let Some(pos) = self.find_tag(tag, start) {
             let entry = self.entries[pos].load(Ordering::SeqCst);
-            if !entry.is_null() && unsafe { (*entry).key == *key } {
-                return unsafe { (*entry).value.load() };
+            // Same as in `insert`.
+            if entry == frozen() {
+                return Lookup::Moved(None);
+            }
+            if !entry.is_null() && unsafe { (*entry).hash == hash && is_match(&(*entry).key) } {
+                return match unsafe { (*entry).value.load_marked() } {
+                    (value, false) => Lookup::Found(value),
+                    (value, true) => Lookup::Moved(value),
+                };
             }
             start = pos + 1;
         }
 
-        let next_ptr = self.next.load(Ordering::SeqCst);
-        if !next_ptr.is_null() {
-            unsafe { &*next_ptr }.get(hash, key)
-        } else {
-            None
+        match self.next.load(Ordering::SeqCst) {
+            next_ptr if next_ptr.is_null() => Lookup::Absent,
+            next_ptr if next_ptr == frozen() => Lookup::Moved(None),
+            next_ptr => unsafe { &*next_ptr }.get(hash, is_match),
         }
     }
 }
 
-impl<K: Clone + Eq, V> VirtualBucket<K, V> {
-    pub(super) fn copy_to(&self, resizer: &Resizer<K, V>) -> u64 {
-        let mut removed = 0;
-        for (entry, hash) in self.entries.iter().zip(self.hashes.iter()) {
+impl<K: Clone, V, A: Allocator> VirtualBucket<K, V, A> {
+    /// Push a clone of the key and the value of each entry of this bucket
+    /// and of its chained buckets to `out`, skipping removed entries.
+    pub(super) fn collect_into(&self, out: &mut alloc::vec::Vec<(K, Arc<V, A>)>) {
+        for entry in &self.entries {
             let entry = entry.load(Ordering::SeqCst);
-            if !entry.is_null() {
+            if !entry.is_null() && entry != frozen() {
                 let entry = unsafe { &*entry };
-                let hash = hash.load(Ordering::SeqCst);
-                match entry.value.load() {
-                    Some(value) => assert!(resizer
-                        .hash_into(hash)
-                        .insert(hash, entry.key.clone(), value, false, 0., 1)
-                        .is_ok()),
-                    None => removed += 1,
+                if let Some(value) = entry.value.load() {
+                    out.push((entry.key.clone(), value));
                 }
             }
         }
-        removed
+
+        let next_ptr = self.next.load(Ordering::SeqCst);
+        if !next_ptr.is_null() && next_ptr != frozen() {
+            unsafe { &*next_ptr }.collect_into(out);
+        }
     }
 }
 
-impl<K, V> Drop for VirtualBucket<K, V> {
-    fn drop(&mut self) {
-        let ptr = self.next.load(Ordering::SeqCst);
-        if !ptr.is_null() {
-            unsafe {
-                Box::from_raw(ptr);
+impl<K: Clone + Eq, V, A: Allocator + Clone> VirtualBucket<K, V, A> {
+    /// Freeze this bucket and its chained buckets, and put the values of
+    /// their entries into `table` if their key is not there yet. Several
+    /// threads can copy the same bucket concurrently: all of them put the
+    /// same frozen values. Return the number of removed entries that this
+    /// call froze, which are not copied.
+    pub(super) fn copy_to(&self, table: &Buckets<K, V, A>) -> u64 {
+        let tags = self.freeze_tags();
+        let mut removed = 0;
+        for (j, entry) in self.entries.iter().enumerate() {
+            if (tags[j / 8] >> (j % 8 * 8)) as u8 == FROZEN_TAG {
+                continue;
+            }
+
+            let null = core::ptr::null_mut();
+            let ptr =
+                match entry.compare_exchange(null, frozen(), Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(..) => continue,
+                    Err(ptr) if ptr == frozen() => continue,
+                    Err(ptr) => ptr,
+                };
+
+            let entry = unsafe { &*ptr };
+            let first = entry.value.mark();
+            match entry.value.load() {
+                Some(value) => table.put_if_absent(entry.hash, &entry.key, &value),
+                None if first => removed += 1,
+                None => (),
             }
         }
 
+        let null = core::ptr::null_mut();
+        match self
+            .next
+            .compare_exchange(null, frozen(), Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(..) => removed,
+            Err(next_ptr) if next_ptr == frozen() => removed,
+            Err(next_ptr) => removed + unsafe { &*next_ptr }.copy_to(table),
+        }
+    }
+}
+
+impl<K, V, A: Allocator> VirtualBucket<K, V, A> {
+    /// Free the entries and the chained buckets of this bucket.
+    ///
+    /// Safety: `alloc` must be the allocator of the table, and the bucket
+    /// must not be used anymore.
+    pub(super) unsafe fn free(&self, alloc: &A) {
+        let ptr = self.next.load(Ordering::SeqCst);
+        if !ptr.is_null() && ptr != frozen() {
+            (*ptr).free(alloc);
+            drop(Box::from_raw_in(ptr, alloc));
+        }
+
         for bucket in &self.entries {
             let ptr = bucket.load(Ordering::SeqCst);
-            if !ptr.is_null() {
-                unsafe {
-                    Box::from_raw(ptr);
-                }
+            if !ptr.is_null() && ptr != frozen() {
+                drop(Box::from_raw_in(ptr, alloc));
             }
         }
     }
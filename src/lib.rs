@@ -1,18 +1,42 @@
+//! Without the default `std` feature, the crate is `no_std` and only needs
+//! `alloc`.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod atomic_arc;
 pub mod hash_map;
+mod sync;
+
+/// Shorten the stress loops of the tests under Miri, which is orders of
+/// magnitude slower. The unit tests run clean under Miri, which also
+/// emulates weak memory, with `MIRIFLAGS=-Zmiri-strict-provenance cargo
+/// +nightly miri test --lib --features serde`, as in CI.
+#[cfg(test)]
+fn scaled(iterations: usize) -> usize {
+    if cfg!(miri) {
+        iterations / 1000 + 1
+    } else {
+        iterations
+    }
+}
 
 #[test]
 fn test_atomic_arc() {
+    use atomic_arc::{Arc, AtomicArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
     #[derive(Debug)]
     struct Foo(i32);
 
     impl Drop for Foo {
         fn drop(&mut self) {
-            println!("drop {}", self.0);
+            DROPS.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    use atomic_arc::{Arc, AtomicArc};
     let x = AtomicArc::new(Arc::new(Foo(4)));
     let y = x.load();
     let z = y.clone();
@@ -20,15 +44,24 @@ fn test_atomic_arc() {
     x.store(xx.clone());
     let new_x = AtomicArc::new(xx);
 
-    println!("x -> {}", x.load().0);
-    println!("new_x -> {}", new_x.load().0);
-    println!("z: {}", z.0);
-    println!("y: {}", y.0);
+    assert_eq!(x.load().0, 5);
+    assert_eq!(new_x.load().0, 5);
+    assert_eq!((y.0, z.0), (4, 4));
+    drop(y);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+    drop(z);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(x);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(new_x);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2);
 
     let x = AtomicArc::new_nullable(None);
-    println!("{:?}", x.load());
+    assert!(x.load().is_none());
     x.store(Some(Arc::new(Foo(7))));
-    println!("{:?}", x.load());
+    assert_eq!(x.load().unwrap().0, 7);
+    x.store(None);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 3);
 }
 
 #[test]
@@ -45,3 +78,922 @@ fn test_hash_map() {
         println!("{:?}", x.get(&i));
     }
 }
+
+#[test]
+fn test_raw_api() {
+    use hash_map::HashMap;
+
+    let x: HashMap<i32, i32> = HashMap::new();
+    let y: HashMap<i32, i32> = HashMap::new();
+    for i in 0..64 {
+        let hash = x.hash(&i);
+        x.raw_insert(hash, i, i * 2);
+        y.raw_insert(hash, i, i * 3);
+    }
+    for i in 0..64 {
+        let hash = x.hash(&i);
+        assert_eq!(x.raw_get(hash, |k| *k == i).map(|v| *v), Some(i * 2));
+        assert_eq!(y.raw_get(hash, |k| *k == i).map(|v| *v), Some(i * 3));
+        assert_eq!(*x.get(&i).unwrap(), i * 2);
+    }
+
+    // A zero hash is remapped consistently across raw methods.
+    x.raw_insert(0, 100, 100);
+    assert_eq!(x.raw_get(0, |k| *k == 100).map(|v| *v), Some(100));
+    x.raw_remove(0, |k| *k == 100);
+    assert!(x.raw_get(0, |k| *k == 100).is_none());
+
+    for i in 0..32 {
+        x.raw_remove(x.hash(&i), |k| *k == i);
+    }
+    for i in 0..64 {
+        assert_eq!(x.get(&i).is_some(), i >= 32);
+    }
+}
+
+#[test]
+fn test_batch() {
+    use hash_map::HashMap;
+
+    let x: HashMap<i32, i32> = HashMap::new();
+    x.insert_many((0..1000).map(|i| (i, i + 1)));
+    let keys = (0..1100).collect::<Vec<_>>();
+    for (i, value) in x.get_many(&keys).into_iter().enumerate() {
+        assert_eq!(
+            value.map(|v| *v),
+            if i < 1000 { Some(i as i32 + 1) } else { None }
+        );
+    }
+
+    x.remove_many(&keys[..500]);
+    for (i, value) in x.get_many(&keys).into_iter().enumerate() {
+        assert_eq!(value.is_some(), (500..1000).contains(&i));
+    }
+
+    // The last of duplicate keys wins, as with sequential inserts.
+    x.insert_many((0..100).map(|i| (i % 3, i)));
+    assert_eq!(
+        x.get_many(&[0, 1, 2])
+            .iter()
+            .map(|v| **v.as_ref().unwrap())
+            .collect::<Vec<_>>(),
+        [99, 97, 98]
+    );
+
+    // Items without a size hint are inserted a chunk at a time, and the last
+    // of duplicate keys still wins across chunks.
+    x.insert_many((0..2800).filter(|_| true).map(|i| (i % 7, i)));
+    let values = x.get_many(&(0..7).collect::<Vec<_>>());
+    assert!(values
+        .iter()
+        .enumerate()
+        .all(|(k, v)| **v.as_ref().unwrap() == 2793 + k as i32));
+}
+
+#[test]
+fn test_iter() {
+    use hash_map::HashMap;
+
+    let x: HashMap<i32, i32> = HashMap::new();
+    assert_eq!(x.iter().count(), 0);
+    for i in 0..100 {
+        x.insert(i, i * 2);
+    }
+    for i in 0..50 {
+        x.remove(&i);
+    }
+    let mut entries = x.iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(entries, (50..100).map(|i| (i, i * 2)).collect::<Vec<_>>());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
+    use hash_map::HashMap;
+
+    let x: HashMap<String, Vec<i32>> = HashMap::new();
+    for i in 0..100 {
+        x.insert(i.to_string(), vec![i; 2]);
+    }
+    let json = serde_json::to_string(&x).unwrap();
+    let y: HashMap<String, Vec<i32>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(y.iter().count(), 100);
+    for i in 0..100 {
+        assert_eq!(*y.get(&i.to_string()).unwrap(), [i, i]);
+    }
+    let empty: HashMap<i32, i32> = serde_json::from_str("{}").unwrap();
+    assert_eq!(empty.iter().count(), 0);
+
+    // A size hint from the input does not reserve unbounded memory. The
+    // capped reservation is still too big for Miri to initialize.
+    struct Lying;
+    impl Iterator for Lying {
+        type Item = (u32, u32);
+        fn next(&mut self) -> Option<(u32, u32)> {
+            None
+        }
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (usize::MAX / 2, Some(usize::MAX / 2))
+        }
+    }
+    if !cfg!(miri) {
+        let access = serde::de::value::MapDeserializer::<_, serde::de::value::Error>::new(Lying);
+        let empty: HashMap<u32, u32> = serde::Deserialize::deserialize(access).unwrap();
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    let arc: Arc<str> = serde_json::from_str("\"foo\"").unwrap();
+    assert_eq!(&*arc, "foo");
+    assert_eq!(serde_json::to_string(&arc).unwrap(), "\"foo\"");
+    let atomic: AtomicArc<i32> = serde_json::from_str("7").unwrap();
+    atomic.store(Arc::new(8));
+    assert_eq!(serde_json::to_string(&atomic).unwrap(), "8");
+    let nullable: NullableAtomicArc<i32> = serde_json::from_str("null").unwrap();
+    assert!(nullable.is_none());
+    nullable.store(Some(Arc::new(9)));
+    assert_eq!(serde_json::to_string(&nullable).unwrap(), "9");
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_snapshot() {
+    use atomic_arc::Global;
+    use hash_map::{Checksum, Codec, HashMap};
+    use std::collections::hash_map::RandomState;
+    use std::convert::TryInto;
+    use std::io;
+
+    struct Utf8;
+
+    impl Codec<u64, String> for Utf8 {
+        fn encode_key(&self, key: &u64, out: &mut Vec<u8>) {
+            out.extend_from_slice(&key.to_le_bytes());
+        }
+
+        fn encode_value(&self, value: &String, out: &mut Vec<u8>) {
+            out.extend_from_slice(value.as_bytes());
+        }
+
+        fn decode_key(&self, bytes: &[u8]) -> io::Result<u64> {
+            let bytes = bytes.try_into().map_err(|_| io::ErrorKind::InvalidData)?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+
+        fn decode_value(&self, bytes: &[u8]) -> io::Result<String> {
+            String::from_utf8(bytes.to_vec()).map_err(|_| io::ErrorKind::InvalidData.into())
+        }
+    }
+
+    let n = scaled(10_000) as u64;
+    let x: HashMap<u64, String> = HashMap::new();
+    for i in 0..n {
+        x.insert(i, i.to_string());
+    }
+    fn check<S: std::hash::BuildHasher>(y: &HashMap<u64, String, S>, n: u64) {
+        assert_eq!(y.iter().count() as u64, n);
+        for i in 0..n {
+            assert_eq!(*y.get(&i).unwrap(), i.to_string());
+        }
+    }
+    for checksum in [Checksum::None, Checksum::Fnv1a] {
+        let mut bytes = Vec::new();
+        x.snapshot(&mut bytes, &Utf8, checksum).unwrap();
+        check(&HashMap::restore(&bytes[..], &Utf8).unwrap(), n);
+        // Keys are hashed again with another hasher.
+        let y = HashMap::restore_with_hasher_in(&bytes[..], &Utf8, RandomState::new(), Global);
+        check(&y.unwrap(), n);
+    }
+
+    let mut bytes = Vec::new();
+    x.snapshot(&mut bytes, &Utf8, Checksum::Fnv1a).unwrap();
+    let restore = |bytes: &[u8]| HashMap::<u64, String>::restore(bytes, &Utf8).err().unwrap();
+    assert_eq!(restore(&bytes[1..]).kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        restore(&bytes[..bytes.len() - 1]).kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        restore(&corrupted).to_string(),
+        "snapshot checksum mismatch"
+    );
+    // A corrupted count or record length is an error, not an allocation.
+    let mut corrupted = bytes.clone();
+    corrupted[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_eq!(restore(&corrupted).kind(), io::ErrorKind::UnexpectedEof);
+    let mut corrupted = bytes.clone();
+    corrupted[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(restore(&corrupted).kind(), io::ErrorKind::InvalidData);
+
+    // Without a checksum, hashes are still checked against the keys.
+    let mut corrupted = Vec::new();
+    x.snapshot(&mut corrupted, &Utf8, Checksum::None).unwrap();
+    corrupted[32] ^= 1;
+    assert_eq!(restore(&corrupted).kind(), io::ErrorKind::InvalidData);
+
+    // Entries which are not modified concurrently are in the snapshot.
+    let mut bytes = Vec::new();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in n..2 * n {
+                x.insert(i, i.to_string());
+                x.remove(&(i - n / 2));
+            }
+        });
+        x.snapshot(&mut bytes, &Utf8, Checksum::Fnv1a).unwrap();
+    });
+    let y = HashMap::restore(&bytes[..], &Utf8).unwrap();
+    for (key, value) in y.iter() {
+        assert_eq!(*value, key.to_string());
+    }
+    for i in 0..n / 2 {
+        assert!(y.get(&i).is_some());
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_rayon() {
+    use hash_map::HashMap;
+    use rayon::prelude::*;
+
+    let n = scaled(100_000) as u64;
+    let mut x: HashMap<u64, u64> = (0..n).into_par_iter().map(|i| (i, i * 2)).collect();
+    // Filtered iterators do not know their length.
+    x.par_extend(
+        (n..2 * n)
+            .into_par_iter()
+            .filter(|i| i % 2 == 0)
+            .map(|i| (i, i * 2)),
+    );
+    let mut entries = x.par_iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+    entries.sort();
+    let expected = (0..2 * n)
+        .filter(|&i| i < n || i % 2 == 0)
+        .map(|i| (i, i * 2))
+        .collect::<Vec<_>>();
+    assert_eq!(entries, expected);
+    assert_eq!(
+        x.par_iter().map(|(_, v)| *v).sum::<u64>(),
+        x.iter().map(|(_, v)| *v).sum::<u64>()
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_std_traits() {
+    use hash_map::HashMap;
+
+    let std_map = (0..100)
+        .map(|i| (i, i * 2))
+        .collect::<std::collections::HashMap<_, _>>();
+    let x: HashMap<i32, i32> = HashMap::from(std_map.clone());
+    let mut y: HashMap<i32, i32> = (0..50).map(|i| (i, i * 2)).collect();
+    assert!(x != y);
+    y.extend((50..100).map(|i| (i, i * 2)));
+    assert!(x == y);
+    y.insert(0, 1);
+    assert!(x != y);
+    assert!(x != HashMap::default());
+
+    // The clone does not share values with the original.
+    let z: HashMap<i32, Vec<i32>> = (0..10).map(|i| (i, vec![i])).collect();
+    let w = z.clone();
+    z.insert(0, vec![1]);
+    assert_eq!(*w.get(&0).unwrap(), [0]);
+    assert!(!atomic_arc::Arc::ptr_eq(
+        &z.get(&1).unwrap(),
+        &w.get(&1).unwrap()
+    ));
+
+    let mut entries = (&x).into_iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+    entries.sort();
+    let mut expected = std_map.into_iter().collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(entries, expected);
+    let value = x.iter().find(|(k, _)| *k == 7).unwrap().1;
+    assert_eq!(*value, 14);
+
+    let x: HashMap<&str, i32> = std::iter::once(("a", 1)).collect();
+    assert_eq!(format!("{x:?}"), "{\"a\": 1}");
+}
+
+#[test]
+fn test_hash_collisions() {
+    use hash_map::HashMap;
+
+    // All keys share the same hash, so that they fill several slots of the
+    // same bucket and spill into its chained buckets.
+    let x: HashMap<i32, i32> = HashMap::new();
+    for i in 0..20 {
+        x.raw_insert(42, i, i);
+    }
+    for i in 0..20 {
+        assert_eq!(x.raw_get(42, |k| *k == i).map(|v| *v), Some(i));
+        assert!(x.raw_get(43, |k| *k == i).is_none());
+    }
+    for i in (0..20).step_by(2) {
+        x.raw_remove(42, |k| *k == i);
+    }
+    for i in 0..20 {
+        assert_eq!(x.raw_get(42, |k| *k == i).is_some(), i % 2 == 1);
+    }
+}
+
+#[test]
+fn test_reentrant_drop() {
+    use hash_map::HashMap;
+    use std::sync::OnceLock;
+
+    static MAP: OnceLock<HashMap<u64, Reentrant>> = OnceLock::new();
+
+    // Dropping a value fills the map it was removed from, which resizes it
+    // while the replacing insert is still in progress.
+    struct Reentrant(u64);
+
+    impl Drop for Reentrant {
+        fn drop(&mut self) {
+            for i in 1..=self.0 {
+                MAP.get().unwrap().insert(i, Reentrant(0));
+            }
+        }
+    }
+
+    let n = scaled(1000) as u64;
+    let x = MAP.get_or_init(HashMap::new);
+    x.insert(0, Reentrant(n));
+    x.insert(0, Reentrant(0));
+    assert!((0..=n).all(|i| x.get(&i).is_some()));
+}
+
+#[test]
+fn test_atomic_arc_unbounded_loads() {
+    use atomic_arc::{Arc, AtomicArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Foo;
+
+    impl Drop for Foo {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let x = AtomicArc::new(Arc::new(Foo));
+    let y = x.load();
+    for _ in 0..scaled(3 << 20) {
+        x.load();
+    }
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut kept = Vec::new();
+                for i in 0..scaled(1 << 20) {
+                    let arc = x.load();
+                    if i % 1024 == 0 {
+                        kept.push(arc);
+                    }
+                }
+            });
+        }
+    });
+
+    x.store(Arc::new(Foo));
+    assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+    drop(y);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(x);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+
+    let x = AtomicArc::new_nullable(None::<Arc<Foo>>);
+    for _ in 0..scaled(3 << 20) {
+        assert!(x.load().is_none());
+    }
+}
+
+#[test]
+fn test_guard() {
+    use atomic_arc::{Arc, AtomicArc, Guard};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Foo(i32);
+
+    impl Drop for Foo {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let x = AtomicArc::new(Arc::new(Foo(1)));
+    for _ in 0..scaled(3 << 20) {
+        assert_eq!(x.load_guard().0, 1);
+    }
+
+    let guard = x.load_guard();
+    let upgraded = Guard::upgrade(&guard);
+    let converted: Arc<Foo> = x.load_guard().into();
+    x.store(Arc::new(Foo(2)));
+    assert_eq!(x.load_guard().0, 2);
+    assert_eq!((guard.0, upgraded.0, converted.0), (1, 1, 1));
+
+    drop(guard);
+    drop(upgraded);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+    drop(converted);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(x);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+
+    // Guards dropped after the contents changed take the slow path.
+    let x = AtomicArc::new(Arc::new(Foo(0)));
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..scaled(10_000) {
+                    let guard = x.load_guard();
+                    assert!(guard.0 < 1000);
+                }
+            });
+        }
+        for i in 1..1000 {
+            x.store(Arc::new(Foo(i)));
+        }
+    });
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2 + 999);
+    drop(x);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2 + 1000);
+
+    let x = AtomicArc::new_nullable(None);
+    assert!(x.load_guard().is_none());
+    x.store(Some(Arc::new(Foo(3))));
+    assert_eq!(x.load_guard().unwrap().0, 3);
+}
+
+#[test]
+fn test_compare_exchange() {
+    use atomic_arc::{Arc, AtomicArc};
+
+    let x = AtomicArc::new(Arc::new(1));
+    let one = x.load();
+    let two = Arc::new(2);
+
+    let previous = x.compare_exchange(&one, two.clone()).unwrap();
+    assert_eq!(*previous, 1);
+    let (actual, rejected) = x.compare_exchange(&one, Arc::new(3)).unwrap_err();
+    assert_eq!((*actual, *rejected), (2, 3));
+    assert_eq!(*x.load(), 2);
+
+    assert_eq!(*x.fetch_update(|_| None).unwrap_err(), 2);
+    assert_eq!(*x.fetch_update(|v| Some(Arc::new(**v + 1))).unwrap(), 2);
+    assert_eq!(*x.load(), 3);
+
+    let x = AtomicArc::new(Arc::new(0));
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    x.rcu(|v| Arc::new(**v + 1));
+                }
+            });
+        }
+    });
+    assert_eq!(*x.load(), 4000);
+
+    let x = AtomicArc::new_nullable(None);
+    assert!(x
+        .compare_exchange(&None, Some(Arc::new(1)))
+        .unwrap()
+        .is_none());
+    let (actual, rejected) = x.compare_exchange(&None, None).unwrap_err();
+    assert_eq!((actual.map(|v| *v), rejected.is_none()), (Some(1), true));
+}
+
+#[test]
+fn test_weak() {
+    use atomic_arc::{Arc, AtomicArc, Weak};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Foo(i32);
+
+    impl Drop for Foo {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let x = Arc::new(Foo(1));
+    let weak = Arc::downgrade(&x);
+    assert_eq!(weak.upgrade().unwrap().0, 1);
+
+    // The value is kept alive by an `AtomicArc<T>` holding it.
+    let atomic = AtomicArc::new(x);
+    assert_eq!(weak.clone().upgrade().unwrap().0, 1);
+    atomic.store(Arc::new(Foo(2)));
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    assert!(weak.upgrade().is_none());
+
+    let y = atomic.load();
+    let x = AtomicArc::new_weak(Arc::downgrade(&y));
+    for _ in 0..scaled(3 << 20) {
+        assert_eq!(x.load().upgrade().unwrap().0, 2);
+    }
+    let z = Arc::new(Foo(3));
+    let previous = x.swap(Arc::downgrade(&z));
+    assert_eq!(previous.upgrade().unwrap().0, 2);
+    assert_eq!(x.load().upgrade().unwrap().0, 3);
+
+    drop((atomic, y));
+    assert_eq!(DROPS.load(Ordering::Relaxed), 2);
+    assert!(previous.upgrade().is_none());
+    drop(z);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+    assert!(x.load().upgrade().is_none());
+
+    // Weak references break cycles.
+    struct Node {
+        parent: Option<Weak<Node>>,
+        _foo: Foo,
+    }
+
+    let parent = Arc::new(Node {
+        parent: None,
+        _foo: Foo(4),
+    });
+    let child = Arc::new(Node {
+        parent: Some(Arc::downgrade(&parent)),
+        _foo: Foo(5),
+    });
+    assert!(child.parent.as_ref().unwrap().upgrade().is_some());
+    drop(parent);
+    assert!(child.parent.as_ref().unwrap().upgrade().is_none());
+    drop(child);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 5);
+}
+
+#[test]
+fn test_arc_api() {
+    use atomic_arc::{Arc, AtomicArc, Weak};
+
+    let mut x = Arc::new(1);
+    let y = x.clone();
+    assert!(Arc::ptr_eq(&x, &y));
+    assert!(!Arc::ptr_eq(&x, &Arc::new(1)));
+    assert_eq!(Arc::strong_count(&x), 2);
+    assert!(Arc::get_mut(&mut x).is_none());
+    drop(y);
+    *Arc::get_mut(&mut x).unwrap() += 1;
+    assert_eq!(*x, 2);
+
+    // `get_mut` must fail while a weak reference or an `AtomicArc<T>` exists.
+    let weak = Arc::downgrade(&x);
+    assert_eq!(Arc::weak_count(&x), 1);
+    assert!(Arc::get_mut(&mut x).is_none());
+    drop(weak);
+    let atomic = AtomicArc::new(x.clone());
+    assert!(Arc::get_mut(&mut x).is_none());
+    drop(atomic);
+    assert!(Arc::get_mut(&mut x).is_some());
+
+    // Copy-on-write.
+    let mut y = x.clone();
+    *Arc::make_mut(&mut y) += 1;
+    assert_eq!((*x, *y), (2, 3));
+    *Arc::make_mut(&mut y) += 1;
+    assert_eq!(*y, 4);
+
+    // Weak references alone do not make `make_mut` clone the value.
+    #[derive(Debug, PartialEq)]
+    struct NoClone(i32);
+    impl Clone for NoClone {
+        fn clone(&self) -> Self {
+            panic!("cloned");
+        }
+    }
+    let mut z = Arc::new(NoClone(1));
+    let weak = Arc::downgrade(&z);
+    Arc::make_mut(&mut z).0 += 1;
+    assert_eq!(*z, NoClone(2));
+    assert!(weak.upgrade().is_none());
+    assert_eq!((Arc::strong_count(&z), Arc::weak_count(&z)), (1, 0));
+
+    let y = x.clone();
+    let x = Arc::try_unwrap(x).unwrap_err();
+    drop(y);
+    let weak = Arc::downgrade(&x);
+    assert_eq!(Arc::try_unwrap(x).unwrap(), 2);
+    assert!(weak.upgrade().is_none());
+
+    let x = Arc::new(String::from("foo"));
+    let y = x.clone();
+    assert!(Arc::into_inner(x).is_none());
+    assert_eq!(Arc::into_inner(y).unwrap(), "foo");
+
+    let x = Arc::new(String::from("bar"));
+    let ptr = Arc::into_raw(x);
+    let x: Arc<String> = unsafe { Arc::from_raw(ptr) };
+    assert_eq!(*x, "bar");
+
+    struct Node {
+        me: Weak<Node>,
+        value: i32,
+    }
+
+    let node = Arc::new_cyclic(|me| {
+        assert!(me.upgrade().is_none());
+        Node {
+            me: me.clone(),
+            value: 5,
+        }
+    });
+    assert_eq!(node.me.upgrade().unwrap().value, 5);
+    assert_eq!(Arc::weak_count(&node), 1);
+
+    // Only one of the last two owners gets the value.
+    for _ in 0..100 {
+        let x = Arc::new(0);
+        let y = x.clone();
+        let values = std::thread::scope(|s| {
+            let t = s.spawn(move || Arc::into_inner(y));
+            (Arc::into_inner(x), t.join().unwrap())
+        });
+        assert!(values.0.is_some() != values.1.is_some());
+    }
+}
+
+#[test]
+fn test_unsized() {
+    use atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let s: Arc<str> = Arc::from("foo");
+    assert_eq!(&*s, "foo");
+    let s: Arc<str> = Arc::from(String::from("bar"));
+    assert_eq!(&*s.clone(), "bar");
+    let v: Arc<[u64]> = Arc::from(vec![1, 2, 3]);
+    assert_eq!(*v, [1, 2, 3]);
+    let v: Arc<[String]> = Arc::from(&[String::from("a"), String::from("b")][..]);
+    assert_eq!(v.len(), 2);
+    let empty: Arc<[()]> = Arc::from(Vec::new());
+    assert!(empty.is_empty());
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    trait Shape: Send + Sync {
+        fn area(&self) -> u64;
+    }
+
+    struct Square(u64);
+    struct Rect(u64, u64, #[allow(dead_code)] [u8; 100]);
+
+    impl Shape for Square {
+        fn area(&self) -> u64 {
+            self.0 * self.0
+        }
+    }
+
+    impl Shape for Rect {
+        fn area(&self) -> u64 {
+            self.0 * self.1
+        }
+    }
+
+    impl Drop for Rect {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let square: Arc<dyn Shape> = unsafe { Arc::new_unsized(Square(3), |ptr| ptr as _) };
+    let rect: Arc<dyn Shape> = unsafe { Arc::new_unsized(Rect(2, 5, [0; 100]), |ptr| ptr as _) };
+    let weak = Arc::downgrade(&rect);
+
+    let atomic = AtomicArc::new(square.clone());
+    assert_eq!(atomic.load().area(), 9);
+    assert_eq!(atomic.load_guard().area(), 9);
+    assert!(atomic.compare_exchange(&square, rect).is_ok());
+    assert_eq!(atomic.load().area(), 10);
+    assert_eq!(weak.upgrade().unwrap().area(), 10);
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    assert!(atomic.load_guard().area() > 0);
+                }
+            });
+        }
+        for i in 0..1000 {
+            atomic.store(unsafe {
+                Arc::new_unsized(Rect(1, i + 1, [0; 100]), |ptr| ptr as *mut dyn Shape)
+            });
+        }
+    });
+    drop(atomic);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1001);
+    assert!(weak.upgrade().is_none());
+
+    let nullable: NullableAtomicArc<str> = AtomicArc::new_nullable(Some(Arc::from("baz")));
+    assert_eq!(&*nullable.load().unwrap(), "baz");
+    nullable.store(None);
+    assert!(nullable.load().is_none());
+}
+
+#[test]
+fn test_arc_traits() {
+    use atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
+
+    let mut v: Vec<Arc<i32>> = vec![3, 1, 2].into_iter().map(Arc::from).collect();
+    v.sort();
+    assert_eq!(v, [Arc::new(1), Arc::new(2), Arc::new(3)]);
+    assert!(Arc::new(1) < Arc::new(2));
+
+    let map = hash_map::HashMap::<Arc<str>, i32>::new();
+    map.insert(Arc::from("foo"), 1);
+    assert_eq!(*map.get(&Arc::from("foo")).unwrap(), 1);
+
+    let mut set = std::collections::HashSet::new();
+    set.insert(Arc::<str>::from("bar"));
+    assert!(set.contains("bar"));
+
+    let s: Arc<String> = Arc::default();
+    assert_eq!(format!("{s}|{:?}", Arc::new("x")), "|\"x\"");
+    assert_eq!(<Arc<str> as AsRef<str>>::as_ref(&Arc::from("baz")), "baz");
+    assert!(Arc::<[u8]>::default().is_empty());
+
+    let atomic = AtomicArc::<i32>::default();
+    assert_eq!(*atomic.load(), 0);
+    atomic.store(Arc::new(5));
+    assert_eq!(format!("{atomic:?}"), "5");
+    let nullable = NullableAtomicArc::<i32>::default();
+    assert_eq!(format!("{nullable:?}"), "None");
+}
+
+#[test]
+fn test_exclusive_access() {
+    use atomic_arc::{Arc, AtomicArc, AtomicWeak, NullableAtomicArc};
+
+    let mut x = AtomicArc::new(Arc::new(1));
+    *x.get_mut().unwrap() += 1;
+    let loaded = x.load();
+    assert!(x.get_mut().is_none());
+    drop(loaded);
+    // The outer count of the load above must be folded back first.
+    *x.get_mut().unwrap() += 1;
+    let weak = Arc::downgrade(&x.load());
+    assert!(x.get_mut().is_none());
+    drop(weak);
+
+    let y = x.load();
+    let inner = x.into_inner();
+    assert!(Arc::ptr_eq(&y, &inner));
+    drop(y);
+    assert_eq!(Arc::try_unwrap(inner).unwrap(), 3);
+
+    let mut x = NullableAtomicArc::new_nullable(Some(Arc::new(String::from("foo"))));
+    assert!(!x.is_none());
+    x.get_mut().unwrap().push_str("bar");
+    assert_eq!(*x.take().unwrap(), "foobar");
+    assert!(x.is_none());
+    assert!(x.take().is_none());
+    assert!(x.get_mut().is_none());
+    assert!(x.into_inner().is_none());
+
+    let arc = Arc::new(4);
+    let weak = AtomicWeak::new_weak(Arc::downgrade(&arc));
+    assert_eq!(*weak.into_inner().upgrade().unwrap(), 4);
+    assert_eq!(Arc::into_inner(arc), Some(4));
+}
+
+#[test]
+fn test_allocator() {
+    use allocator_api2::alloc::{AllocError, Allocator, Global, Layout};
+    use atomic_arc::{Arc, AtomicArc, AtomicWeak};
+    use std::ptr::NonNull;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Forwards to the global allocator, counting the bytes in use.
+    #[derive(Clone)]
+    struct Counting<'a>(&'a AtomicUsize);
+
+    unsafe impl Allocator for Counting<'_> {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.fetch_add(layout.size(), Ordering::Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(layout.size(), Ordering::Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let bytes = AtomicUsize::new(0);
+    let x = Arc::new_in(String::from("foo"), Counting(&bytes));
+    assert!(bytes.load(Ordering::Relaxed) > 0);
+    let atomic = AtomicArc::new(x.clone());
+    let weak = AtomicWeak::new_weak(Arc::downgrade(&x));
+    let mut y = atomic.swap(Arc::new_in(String::from("bar"), Counting(&bytes)));
+    Arc::make_mut(&mut y).push_str("baz");
+    assert_eq!((&**x, &**y, &**atomic.load()), ("foo", "foobaz", "bar"));
+    assert!(weak.load().upgrade().is_some());
+    drop((x, y, atomic));
+    assert!(weak.load().upgrade().is_none());
+    drop(weak);
+    assert_eq!(bytes.load(Ordering::Relaxed), 0);
+
+    let ptr = Arc::into_raw(Arc::new_in(String::from("qux"), Counting(&bytes)));
+    let x: Arc<_, Counting> = unsafe { Arc::from_raw(ptr) };
+    assert_eq!((&**x, Arc::strong_count(&x)), ("qux", 1));
+    drop(x);
+    assert_eq!(bytes.load(Ordering::Relaxed), 0);
+
+    let map = hash_map::HashMap::new_in(Counting(&bytes));
+    for i in 0..1000 {
+        map.insert(i, i.to_string());
+    }
+    let value = map.get(&42).unwrap();
+    for i in 0..500 {
+        map.remove(&i);
+    }
+    assert!(map.get(&42).is_none());
+    assert_eq!(*map.get(&999).unwrap(), "999");
+    drop(map);
+    assert_eq!(*value, "42");
+    assert!(bytes.load(Ordering::Relaxed) > 0);
+    drop(value);
+    assert_eq!(bytes.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_concurrent_map() {
+    use hash_map::HashMap;
+
+    let n = if cfg!(miri) { 50 } else { 1000 };
+    let x: HashMap<String, Vec<usize>> = HashMap::new();
+    std::thread::scope(|s| {
+        for t in 0..4 {
+            let x = &x;
+            s.spawn(move || {
+                for i in 0..n {
+                    x.insert(format!("{t}-{i}"), vec![t, i]);
+                }
+                for i in (0..n).step_by(2) {
+                    x.remove(&format!("{t}-{i}"));
+                }
+            });
+        }
+    });
+    for t in 0..4 {
+        for i in 0..n {
+            let value = x.get(&format!("{t}-{i}"));
+            assert_eq!(value.map(|v| v.to_vec()), (i % 2 == 1).then(|| vec![t, i]));
+        }
+    }
+
+    // The map can be moved to and dropped by another thread.
+    let value = x.get(&"0-1".to_string()).unwrap();
+    std::thread::spawn(move || drop(x)).join().unwrap();
+    assert_eq!(*value, [0, 1]);
+}
+
+#[test]
+fn test_concurrent_resizes() {
+    use hash_map::HashMap;
+
+    // Keys are inserted again and removed while the map grows, so that
+    // updates keep finding their entries frozen and retry on the new table.
+    let n = scaled(5000);
+    for _ in 0..scaled(20) {
+        let x: HashMap<usize, usize> = HashMap::new();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let x = &x;
+                s.spawn(move || {
+                    for i in 0..n {
+                        x.insert(i / 4, t);
+                        x.remove(&(i / 8));
+                        x.insert(i / 8, t);
+                        if i % 3 == t % 3 {
+                            x.remove(&(i / 2));
+                        }
+                    }
+                });
+            }
+        });
+
+        // Removed entries are counted until a resize drops them: grow the
+        // map well past its at most `n / 2` keys.
+        x.reserve(16 * n);
+        assert_eq!(x.items() as usize, x.iter().count());
+    }
+}
@@ -0,0 +1,15 @@
+//! The atomics used throughout the crate. They are the ones of `loom` when
+//! built with `--cfg loom`, so that the models in `tests/loom.rs` can explore
+//! all the interleavings of their operations.
+
+#[cfg(loom)]
+pub(crate) use loom::hint::spin_loop;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+#[cfg(not(loom))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{fence, AtomicPtr, AtomicU32, Ordering};
+#[cfg(not(loom))]
+pub(crate) use portable_atomic::AtomicU64;
@@ -0,0 +1,7 @@
+use hash_map::atomic_arc::Arc;
+use std::cell::Cell;
+
+fn main() {
+    let value = Arc::new(Cell::new(0));
+    std::thread::spawn(move || value.set(1));
+}
@@ -0,0 +1,9 @@
+use hash_map::atomic_arc::{Arc, AtomicArc};
+use std::cell::Cell;
+
+fn main() {
+    let value = AtomicArc::new(Arc::new(Cell::new(0)));
+    std::thread::scope(|s| {
+        s.spawn(|| value.load().set(1));
+    });
+}
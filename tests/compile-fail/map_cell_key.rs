@@ -0,0 +1,7 @@
+use hash_map::hash_map::HashMap;
+use std::cell::Cell;
+
+fn main() {
+    let map: HashMap<Cell<i32>, i32> = HashMap::new();
+    std::thread::spawn(move || drop(map));
+}
@@ -0,0 +1,11 @@
+use hash_map::hash_map::HashMap;
+use std::cell::Cell;
+
+fn main() {
+    let map: HashMap<i32, Cell<i32>> = HashMap::new();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            map.get(&0);
+        });
+    });
+}
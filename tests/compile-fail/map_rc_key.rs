@@ -0,0 +1,7 @@
+use hash_map::hash_map::HashMap;
+use std::rc::Rc;
+
+fn main() {
+    let map: HashMap<Rc<i32>, i32> = HashMap::new();
+    std::thread::spawn(move || drop(map));
+}
@@ -0,0 +1,11 @@
+use hash_map::atomic_arc::{Arc, NullableAtomicArc};
+use std::rc::Rc;
+
+fn main() {
+    let value = NullableAtomicArc::new_nullable(Some(Arc::new(Rc::new(0))));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            value.load();
+        });
+    });
+}
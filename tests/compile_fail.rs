@@ -0,0 +1,10 @@
+//! Check that maps and atomic references to non thread-safe types cannot be
+//! shared or sent between threads.
+
+#[test]
+// Miri cannot run the compiler.
+#[cfg_attr(miri, ignore)]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile-fail/*.rs");
+}
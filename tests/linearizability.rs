@@ -0,0 +1,237 @@
+//! Check that `insert`, `get` and `remove` are linearizable: random
+//! operations run concurrently on a map which starts with a single bucket,
+//! so that it is resized all the time, and the recorded history must match
+//! some sequential execution of a `std::collections::HashMap`.
+//!
+//! Operations on different keys commute, so each key is checked on its own,
+//! which keeps the histories small enough for an exhaustive search in the
+//! style of Wing & Gong, with the memoization of Lowe.
+#![cfg(not(loom))]
+
+use hash_map::hash_map::HashMap;
+use std::collections::HashSet;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 4;
+const OPS_PER_THREAD: usize = 256;
+const KEYS: u64 = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Insert(u64, u64),
+    Get(u64, Option<u64>),
+    Remove(u64),
+    // Not part of the model, only there to start more resizes.
+    Reserve(usize),
+}
+
+impl Op {
+    fn key(&self) -> Option<u64> {
+        match *self {
+            Op::Insert(key, _) | Op::Get(key, _) | Op::Remove(key) => Some(key),
+            Op::Reserve(_) => None,
+        }
+    }
+}
+
+/// An operation with the times of its invocation and response.
+#[derive(Clone, Copy, Debug)]
+struct Event {
+    op: Op,
+    call: Instant,
+    ret: Instant,
+}
+
+/// A xorshift generator, enough to pick operations.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Run random operations from `THREADS` threads on a new map and return
+/// the history of each thread.
+fn run(seed: u64) -> Vec<Vec<Event>> {
+    let map = HashMap::<u64, u64>::new();
+    let barrier = Barrier::new(THREADS);
+
+    std::thread::scope(|s| {
+        let threads = (0..THREADS)
+            .map(|t| {
+                let (map, barrier) = (&map, &barrier);
+                s.spawn(move || {
+                    let mut rng = Rng(seed * THREADS as u64 + t as u64 + 1);
+                    let mut events = Vec::with_capacity(OPS_PER_THREAD);
+                    barrier.wait();
+                    for i in 0..OPS_PER_THREAD {
+                        let key = rng.next(KEYS);
+                        let call = Instant::now();
+                        let op = match rng.next(16) {
+                            0..=5 => {
+                                // Values are unique, so that a read tells
+                                // which insert it comes from.
+                                let value = (t * OPS_PER_THREAD + i) as u64;
+                                map.insert(key, value);
+                                Op::Insert(key, value)
+                            }
+                            6..=11 => Op::Get(key, map.get(&key).map(|v| *v)),
+                            12..=14 => {
+                                map.remove(&key);
+                                Op::Remove(key)
+                            }
+                            _ => {
+                                let additional = rng.next(4 * KEYS) as usize;
+                                map.reserve(additional);
+                                Op::Reserve(additional)
+                            }
+                        };
+                        let ret = Instant::now();
+                        events.push(Event { op, call, ret });
+                    }
+                    events
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    })
+}
+
+/// Return whether the operations of `events`, which all use the same key,
+/// can be ordered so that each one takes effect between its invocation and
+/// its response, and reads see the last value written before them.
+fn is_linearizable(events: &[Event]) -> bool {
+    fn search(
+        events: &[Event],
+        linearized: &mut Vec<bool>,
+        value: Option<u64>,
+        failed: &mut HashSet<(Vec<bool>, Option<u64>)>,
+    ) -> bool {
+        // Only an operation invoked before every pending one has returned
+        // can come next.
+        let deadline = match events
+            .iter()
+            .zip(linearized.iter())
+            .filter(|(_, done)| !**done)
+            .map(|(event, _)| event.ret)
+            .min()
+        {
+            Some(deadline) => deadline,
+            None => return true,
+        };
+        if failed.contains(&(linearized.clone(), value)) {
+            return false;
+        }
+
+        for (i, event) in events.iter().enumerate() {
+            if linearized[i] || event.call > deadline {
+                continue;
+            }
+            let next = match event.op {
+                Op::Insert(_, inserted) => Some(inserted),
+                Op::Get(_, read) if read == value => value,
+                Op::Get(..) => continue,
+                Op::Remove(_) => None,
+                Op::Reserve(_) => unreachable!(),
+            };
+            linearized[i] = true;
+            if search(events, linearized, next, failed) {
+                return true;
+            }
+            linearized[i] = false;
+        }
+
+        failed.insert((linearized.clone(), value));
+        false
+    }
+
+    search(
+        events,
+        &mut vec![false; events.len()],
+        None,
+        &mut HashSet::new(),
+    )
+}
+
+/// Print one event per line, with times in nanoseconds since the first
+/// invocation.
+fn format_history(events: &[Event]) -> String {
+    let start = events.iter().map(|event| event.call).min();
+    events
+        .iter()
+        .map(|event| {
+            let since = |at: Instant| (at - start.unwrap()).as_nanos();
+            format!(
+                "{:>10} {:>10} {:?}\n",
+                since(event.call),
+                since(event.ret),
+                event.op
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn linearizable_histories() {
+    let rounds = if cfg!(miri) { 2 } else { 500 };
+    for seed in 0..rounds {
+        let history = run(seed).concat();
+        for key in 0..KEYS {
+            let mut events = history
+                .iter()
+                .filter(|event| event.op.key() == Some(key))
+                .copied()
+                .collect::<Vec<_>>();
+            events.sort_by_key(|event| event.call);
+            assert!(
+                is_linearizable(&events),
+                "history of key {} with seed {} is not linearizable:\n{}",
+                key,
+                seed,
+                format_history(&events)
+            );
+        }
+    }
+}
+
+#[test]
+fn checker_rejects_stale_reads() {
+    let start = Instant::now();
+    let event = |op, call, ret| Event {
+        op,
+        call: start + Duration::from_nanos(call),
+        ret: start + Duration::from_nanos(ret),
+    };
+
+    // A read overlapping a write may see either value.
+    assert!(is_linearizable(&[
+        event(Op::Insert(0, 1), 0, 1),
+        event(Op::Insert(0, 2), 2, 5),
+        event(Op::Get(0, Some(1)), 3, 4),
+    ]));
+    // A read after the write completed must see it.
+    assert!(!is_linearizable(&[
+        event(Op::Insert(0, 1), 0, 1),
+        event(Op::Insert(0, 2), 2, 3),
+        event(Op::Get(0, Some(1)), 4, 5),
+    ]));
+    // Two reads cannot see two concurrent writes in opposite orders.
+    assert!(!is_linearizable(&[
+        event(Op::Insert(0, 1), 0, 9),
+        event(Op::Insert(0, 2), 0, 9),
+        event(Op::Get(0, Some(1)), 1, 2),
+        event(Op::Get(0, Some(2)), 3, 4),
+        event(Op::Get(0, Some(1)), 5, 6),
+    ]));
+    // A removed entry is gone.
+    assert!(!is_linearizable(&[
+        event(Op::Insert(0, 1), 0, 1),
+        event(Op::Remove(0), 2, 3),
+        event(Op::Get(0, Some(1)), 4, 5),
+    ]));
+}
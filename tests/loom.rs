@@ -0,0 +1,213 @@
+//! Model checking of the atomic protocols with loom. Run with
+//! `RUSTFLAGS="--cfg loom" cargo test --release --test loom`, as in CI,
+//! optionally with `LOOM_MAX_PREEMPTIONS` to bound the models further. The
+//! map models are bounded to three preemptions, which checks them in
+//! minutes, where a fourth takes well over ten per model.
+#![cfg(loom)]
+
+use hash_map::atomic_arc::{Arc, AtomicArc, NullableAtomicArc};
+use hash_map::hash_map::HashMap;
+use loom::thread;
+
+/// Run `f` under loom, bounding the number of preemptions of the models
+/// which are too big to be checked exhaustively.
+fn model(preemption_bound: Option<usize>, f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = preemption_bound;
+    }
+    builder.check(f);
+}
+
+#[test]
+fn atomic_arc_load_store() {
+    model(None, || {
+        let first = Arc::new(1);
+        let weak = Arc::downgrade(&first);
+        let x = Arc::new(AtomicArc::new(first));
+
+        let t = {
+            let x = x.clone();
+            thread::spawn(move || x.store(Arc::new(2)))
+        };
+        let value = *x.load();
+        assert!(value == 1 || value == 2);
+        let guard = x.load_guard();
+        assert!(*guard == value || *guard == 2);
+        drop(guard);
+
+        t.join().unwrap();
+        assert_eq!(*x.load(), 2);
+        assert!(weak.upgrade().is_none());
+    });
+}
+
+#[test]
+fn atomic_arc_swap() {
+    model(None, || {
+        let x = Arc::new(AtomicArc::new(Arc::new(0)));
+
+        let threads = (1..3)
+            .map(|i| {
+                let x = x.clone();
+                thread::spawn(move || *x.swap(Arc::new(i)))
+            })
+            .collect::<Vec<_>>();
+        let mut seen = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        seen.push(*x.load());
+
+        // Each value was swapped out exactly once, or is the last one.
+        seen.sort();
+        assert_eq!(seen, [0, 1, 2]);
+    });
+}
+
+#[test]
+fn atomic_arc_try_store() {
+    model(None, || {
+        let current = Arc::new(0);
+        let x = Arc::new(AtomicArc::new(current.clone()));
+
+        let threads = (1..3)
+            .map(|i| {
+                let (x, current) = (x.clone(), current.clone());
+                thread::spawn(move || x.try_store(&current, Arc::new(i)))
+            })
+            .collect::<Vec<_>>();
+        let stored = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(stored.iter().filter(|stored| **stored).count(), 1);
+        let winner = stored.iter().position(|stored| *stored).unwrap() + 1;
+        assert_eq!(*x.load(), winner as i32);
+        let mut last = Arc::try_unwrap(x).ok().unwrap().into_inner();
+        assert!(Arc::get_mut(&mut last).is_some());
+    });
+}
+
+#[test]
+fn nullable_atomic_arc_take() {
+    model(None, || {
+        let value = Arc::new(1);
+        let weak = Arc::downgrade(&value);
+        let x = Arc::new(NullableAtomicArc::new_nullable(Some(value)));
+
+        let t = {
+            let x = x.clone();
+            thread::spawn(move || x.load().map(|value| *value))
+        };
+        let taken = x.take();
+        assert_eq!(taken.as_deref(), Some(&1));
+        drop(taken);
+
+        assert!(matches!(t.join().unwrap(), None | Some(1)));
+        assert!(x.is_none());
+        assert!(weak.upgrade().is_none());
+    });
+}
+
+#[test]
+fn map_insert_same_key() {
+    model(Some(3), || {
+        let map = Arc::new(HashMap::<i32, i32>::new());
+
+        let threads = (1..3)
+            .map(|i| {
+                let map = map.clone();
+                thread::spawn(move || map.insert(0, i))
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // Only one of the two entries remains.
+        assert!(matches!(map.get(&0).map(|v| *v), Some(1) | Some(2)));
+        map.remove(&0);
+        assert!(map.get(&0).is_none());
+    });
+}
+
+#[test]
+fn map_insert_remove() {
+    model(Some(3), || {
+        let map = Arc::new(HashMap::<i32, i32>::new());
+        map.insert(1, 1);
+
+        let t = {
+            let map = map.clone();
+            thread::spawn(move || map.insert(0, 0))
+        };
+        map.remove(&0);
+        let value = map.get(&0).map(|v| *v);
+        assert!(matches!(value, None | Some(0)));
+        t.join().unwrap();
+
+        assert!(matches!(map.get(&0).map(|v| *v), None | Some(0)));
+        assert_eq!(map.get(&1).map(|v| *v), Some(1));
+    });
+}
+
+#[test]
+fn map_insert_during_resize() {
+    model(Some(3), || {
+        let map = Arc::new(HashMap::<i32, i32>::new());
+        map.insert(0, 0);
+
+        let t = {
+            let map = map.clone();
+            thread::spawn(move || map.reserve(16))
+        };
+        map.insert(1, 1);
+        t.join().unwrap();
+
+        // Neither the entry copied by the resize nor the one racing with it
+        // are lost.
+        assert_eq!(map.get(&0).map(|v| *v), Some(0));
+        assert_eq!(map.get(&1).map(|v| *v), Some(1));
+    });
+}
+
+#[test]
+fn map_remove_during_resize() {
+    model(Some(3), || {
+        let map = Arc::new(HashMap::<i32, i32>::new());
+        map.insert(0, 0);
+
+        let t = {
+            let map = map.clone();
+            thread::spawn(move || map.reserve(16))
+        };
+        map.remove(&0);
+        t.join().unwrap();
+
+        // The resize does not bring back the entry it copied.
+        assert!(map.get(&0).is_none());
+    });
+}
+
+#[test]
+fn map_insert_during_two_resizes() {
+    model(Some(3), || {
+        let map = Arc::new(HashMap::<i32, i32>::new());
+
+        let t = {
+            let map = map.clone();
+            thread::spawn(move || {
+                map.reserve(8);
+                map.reserve(16);
+            })
+        };
+        map.insert(1, 1);
+        t.join().unwrap();
+
+        // The insert may complete in a table which is already replaced, and
+        // must then be carried over to the last one.
+        assert_eq!(map.get(&1).map(|v| *v), Some(1));
+    });
+}
@@ -0,0 +1,196 @@
+//! Compare `HashMap` against `std::collections::HashMap` on arbitrary
+//! sequences of operations, either on a single thread or spread over several
+//! threads taking turns in an order picked by proptest, so that failures can
+//! be shrunk and replayed.
+//!
+//! Every sequence also runs with a hasher sending all the keys to a handful
+//! of buckets, so that chained buckets and their resizes get exercised.
+#![cfg(not(any(loom, miri)))]
+
+use hash_map::atomic_arc::Global;
+use hash_map::hash_map::HashMap;
+use proptest::prelude::*;
+use proptest::test_runner::FileFailurePersistence;
+use std::collections::HashMap as StdHashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const THREADS: usize = 3;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Insert(u8, u32),
+    Get(u8),
+    Remove(u8),
+    Reserve(u8),
+    InsertMany(Vec<(u8, u32)>),
+    GetMany(Vec<u8>),
+    RemoveMany(Vec<u8>),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (any::<u8>(), any::<u32>()).prop_map(|(k, v)| Op::Insert(k, v)),
+        3 => any::<u8>().prop_map(Op::Get),
+        2 => any::<u8>().prop_map(Op::Remove),
+        1 => any::<u8>().prop_map(Op::Reserve),
+        1 => prop::collection::vec((any::<u8>(), any::<u32>()), 0..32).prop_map(Op::InsertMany),
+        1 => prop::collection::vec(any::<u8>(), 0..32).prop_map(Op::GetMany),
+        1 => prop::collection::vec(any::<u8>(), 0..32).prop_map(Op::RemoveMany),
+    ]
+}
+
+/// Hash keys to one of 4 values, so that they pile up in the same buckets.
+#[derive(Default)]
+struct Colliding(u64);
+
+impl Hasher for Colliding {
+    fn finish(&self) -> u64 {
+        self.0 % 4
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.wrapping_mul(31).wrapping_add(byte as u64);
+        }
+    }
+}
+
+/// Apply `op` to `map` and return what it read, if anything.
+fn apply<S: BuildHasher>(map: &HashMap<u8, u32, S>, op: &Op) -> Vec<Option<u32>> {
+    match op {
+        Op::Insert(key, value) => map.insert(*key, *value),
+        Op::Get(key) => return vec![map.get(key).map(|v| *v)],
+        Op::Remove(key) => map.remove(key),
+        Op::Reserve(additional) => map.reserve(*additional as usize),
+        Op::InsertMany(items) => map.insert_many(items.iter().copied()),
+        Op::GetMany(keys) => {
+            return map
+                .get_many(keys)
+                .iter()
+                .map(|v| v.as_deref().copied())
+                .collect()
+        }
+        Op::RemoveMany(keys) => map.remove_many(keys),
+    }
+    Vec::new()
+}
+
+/// Same as `apply`, for the model.
+fn apply_model(model: &mut StdHashMap<u8, u32>, op: &Op) -> Vec<Option<u32>> {
+    match op {
+        Op::Insert(key, value) => {
+            model.insert(*key, *value);
+        }
+        Op::Get(key) => return vec![model.get(key).copied()],
+        Op::Remove(key) => {
+            model.remove(key);
+        }
+        Op::Reserve(_) => (),
+        Op::InsertMany(items) => model.extend(items.iter().copied()),
+        Op::GetMany(keys) => return keys.iter().map(|key| model.get(key).copied()).collect(),
+        Op::RemoveMany(keys) => {
+            for key in keys {
+                model.remove(key);
+            }
+        }
+    }
+    Vec::new()
+}
+
+fn check_contents<S: BuildHasher>(
+    map: &HashMap<u8, u32, S>,
+    model: &StdHashMap<u8, u32>,
+) -> Result<(), TestCaseError> {
+    for key in 0..=u8::MAX {
+        prop_assert_eq!(
+            map.get(&key).map(|v| *v),
+            model.get(&key).copied(),
+            "key {}",
+            key
+        );
+    }
+    Ok(())
+}
+
+fn check_sequential<S: BuildHasher>(
+    map: HashMap<u8, u32, S>,
+    ops: &[Op],
+) -> Result<(), TestCaseError> {
+    let mut model = StdHashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        prop_assert_eq!(
+            apply(&map, op),
+            apply_model(&mut model, op),
+            "step {}: {:?}",
+            i,
+            op
+        );
+    }
+    check_contents(&map, &model)
+}
+
+/// Run each step of `schedule` on the thread it names, one step at a time
+/// and in order, then compare the reads with the model.
+fn check_interleaved<S: BuildHasher + Sync>(
+    map: HashMap<u8, u32, S>,
+    schedule: &[(usize, Op)],
+) -> Result<(), TestCaseError> {
+    let turn = AtomicUsize::new(0);
+    let mut reads = vec![Vec::new(); schedule.len()];
+    std::thread::scope(|s| {
+        let threads = (0..THREADS)
+            .map(|t| {
+                let (map, turn) = (&map, &turn);
+                s.spawn(move || {
+                    let mut reads = Vec::new();
+                    for (i, (_, op)) in schedule.iter().enumerate().filter(|(_, (u, _))| *u == t) {
+                        while turn.load(Ordering::Acquire) != i {
+                            std::thread::yield_now();
+                        }
+                        reads.push((i, apply(map, op)));
+                        turn.store(i + 1, Ordering::Release);
+                    }
+                    reads
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            for (i, read) in thread.join().unwrap() {
+                reads[i] = read;
+            }
+        }
+    });
+
+    let mut model = StdHashMap::new();
+    for (i, (t, op)) in schedule.iter().enumerate() {
+        prop_assert_eq!(
+            &reads[i],
+            &apply_model(&mut model, op),
+            "step {} on thread {}: {:?}",
+            i,
+            t,
+            op
+        );
+    }
+    check_contents(&map, &model)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        failure_persistence: Some(Box::new(FileFailurePersistence::WithSource("proptest-regressions"))),
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn sequential(ops in prop::collection::vec(op(), 0..256)) {
+        check_sequential(HashMap::new(), &ops)?;
+        check_sequential(HashMap::with_hasher_in(BuildHasherDefault::<Colliding>::default(), Global), &ops)?;
+    }
+
+    #[test]
+    fn interleaved(schedule in prop::collection::vec((0..THREADS, op()), 0..128)) {
+        check_interleaved(HashMap::new(), &schedule)?;
+        check_interleaved(HashMap::with_hasher_in(BuildHasherDefault::<Colliding>::default(), Global), &schedule)?;
+    }
+}